@@ -0,0 +1,12 @@
+INSERT INTO bundles(id, name, price, active)
+VALUES
+  (1, '3 sodavand for 20 kr',  2000, true),
+  (2, 'Øl og café for 20 kr',  2000, true),
+  (3, 'Inaktiv',               0,    false);
+
+INSERT INTO bundle_items(bundle_id, product_id, category_id, amount)
+VALUES
+  (1, NULL, 1,    3),
+  (2, 11,   NULL, 1),
+  (2, 12,   NULL, 1),
+  (3, 1,    NULL, 1);
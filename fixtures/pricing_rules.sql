@@ -0,0 +1,8 @@
+-- Rules are relative to the current day so they are in effect, or not, whenever the tests run
+INSERT INTO pricing_rules(product_id, category_id, weekday, starts_at, ends_at, fixed_price, discount_percent)
+VALUES
+  (1,    NULL, NULL,                                                                   '00:00', '24:00', 500,  NULL),
+  (NULL, 1,    EXTRACT(ISODOW FROM now() AT TIME ZONE local_time_zone()),              '00:00', '24:00', NULL, 15),
+  (11,   NULL, EXTRACT(ISODOW FROM now() AT TIME ZONE local_time_zone())::int % 7 + 1, '00:00', '24:00', 100,  NULL),
+  (12,   NULL, NULL,                                                                   '00:00', '24:00', 2000, NULL),
+  (12,   NULL, NULL,                                                                   '00:00', '24:00', NULL, 10);
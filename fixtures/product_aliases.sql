@@ -5,4 +5,14 @@ VALUES
   ('inactive', 3),
   ('inactive_timestamp', 4),
   ('expensive', 5),
-  ('overflow', 6);
+  ('overflow', 6),
+  ('limited', 7),
+  ('sold_out', 8),
+  ('rationed', 9),
+  ('restricted', 10),
+  ('øl', 11),
+  ('café', 12),
+  ('sodavand', 13),
+  ('sødavand', 14),
+  ('active_timestamp', 15),
+  ('scheduled', 16);
@@ -0,0 +1,5 @@
+INSERT INTO product_categories(id, name, sort_order)
+VALUES
+  (1, 'Sodavand', 1);
+
+UPDATE products SET category_id = 1 WHERE id IN (13, 14);
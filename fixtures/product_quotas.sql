@@ -0,0 +1,3 @@
+INSERT INTO product_quotas(product_id, max_amount, period, starts_at, ends_at)
+VALUES
+  (9, 2, 'day', NULL, NULL);
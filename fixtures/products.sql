@@ -1,8 +1,37 @@
-INSERT INTO products(id, name, price, active, deactivate_after_timestamp)
+INSERT INTO products(id, name, active, activate_after_timestamp, deactivate_after_timestamp, stock, age_restricted)
 VALUES 
-  (1, 'Enabled',                  700 ,         true,  NULL),
-  (2, 'No aliases',               1200,         true,  NULL),
-  (3, 'Inactive',                 200,          false, NULL),
-  (4, 'Deactivated by Timestamp', 30000,        true,  '2024-09-01'),
-  (5, 'Expensive',                100000,       true,  NULL),
-  (6, 'Overflow trigger',         100000000000, true,  NULL);
+  (1,  'Enabled',                  true,  NULL,         NULL,         NULL, false),
+  (2,  'No aliases',               true,  NULL,         NULL,         NULL, false),
+  (3,  'Inactive',                 false, NULL,         NULL,         NULL, false),
+  (4,  'Deactivated by Timestamp', true,  NULL,         '2024-09-01', NULL, false),
+  (5,  'Expensive',                true,  NULL,         NULL,         NULL, false),
+  (6,  'Overflow trigger',         true,  NULL,         NULL,         NULL, false),
+  (7,  'Limited',                  true,  NULL,         NULL,         2,    false),
+  (8,  'Sold out',                 true,  NULL,         NULL,         0,    false),
+  (9,  'Rationed',                 true,  NULL,         NULL,         NULL, false),
+  (10, 'Restricted',               true,  NULL,         NULL,         NULL, true),
+  (11, 'Øl',                       true,  NULL,         NULL,         NULL, false),
+  (12, 'Café',                     true,  NULL,         NULL,         NULL, false),
+  (13, 'Sodavand',                 true,  NULL,         NULL,         NULL, false),
+  (14, 'Sødavand',                 true,  NULL,         NULL,         NULL, false),
+  (15, 'Activated by Timestamp',   true,  '2024-09-01', NULL,         NULL, false),
+  (16, 'Scheduled',                true,  '2999-12-24', NULL,         NULL, false);
+
+INSERT INTO product_prices(product_id, price, valid_from)
+VALUES
+  (1,  700,          '-infinity'),
+  (2,  1200,         '-infinity'),
+  (3,  200,          '-infinity'),
+  (4,  30000,        '-infinity'),
+  (5,  100000,       '-infinity'),
+  (6,  100000000000, '-infinity'),
+  (7,  500,          '-infinity'),
+  (8,  500,          '-infinity'),
+  (9,  100,          '-infinity'),
+  (10, 700,          '-infinity'),
+  (11, 1000,         '-infinity'),
+  (12, 1500,         '-infinity'),
+  (13, 1100,         '-infinity'),
+  (14, 1100,         '-infinity'),
+  (15, 300,          '-infinity'),
+  (16, 300,          '-infinity');
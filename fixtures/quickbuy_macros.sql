@@ -0,0 +1,5 @@
+INSERT INTO quickbuy_macros(user_id, name, expansion)
+VALUES
+  (1, 'morgen', 'enabled 2xrationed'),
+  (1, 'broken', 'enabled nope'),
+  (2, 'aften',  'limited');
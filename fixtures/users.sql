@@ -1,3 +1,5 @@
-INSERT INTO users(id, username, email, notes)
+INSERT INTO users(id, username, email, notes, credit_limit, birth_date)
 VALUES
-  (1, 'test_user', 'test@email.com', 'test user');
+  (1, 'test_user', 'test@email.com', 'test user', 0, '1990-01-01'),
+  (2, 'trusted_user', 'trusted@email.com', 'trusted user with credit', 5000, NULL),
+  (3, 'minor_user', 'minor@email.com', 'underage user', 5000, '2020-01-01');
@@ -0,0 +1,40 @@
+ALTER TABLE users ADD COLUMN balance BIGINT NOT NULL DEFAULT 0;
+
+UPDATE users
+SET balance = (SELECT COALESCE(SUM(amount), 0) FROM deposits WHERE user_id = users.id) - (SELECT COALESCE(SUM(price), 0) FROM sales WHERE user_id = users.id);
+
+CREATE FUNCTION deposits_update_user_balance() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP IN ('UPDATE', 'DELETE') THEN
+    UPDATE users SET balance = balance - OLD.amount WHERE id = OLD.user_id;
+  END IF;
+
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    UPDATE users SET balance = balance + NEW.amount WHERE id = NEW.user_id;
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER deposits_update_user_balance
+  AFTER INSERT OR UPDATE OR DELETE ON deposits
+  FOR EACH ROW EXECUTE FUNCTION deposits_update_user_balance();
+
+CREATE FUNCTION sales_update_user_balance() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP IN ('UPDATE', 'DELETE') THEN
+    UPDATE users SET balance = balance + OLD.price WHERE id = OLD.user_id;
+  END IF;
+
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    UPDATE users SET balance = balance - NEW.price WHERE id = NEW.user_id;
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER sales_update_user_balance
+  AFTER INSERT OR UPDATE OR DELETE ON sales
+  FOR EACH ROW EXECUTE FUNCTION sales_update_user_balance();
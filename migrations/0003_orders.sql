@@ -0,0 +1,14 @@
+CREATE TABLE orders (
+  id BIGINT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+  timestamp TIMESTAMPTZ NOT NULL DEFAULT now(),
+  user_id INT NOT NULL,
+
+  CONSTRAINT fk_user
+    FOREIGN KEY(user_id)
+      REFERENCES users(id)
+);
+
+-- Sales from before orders were introduced do not belong to an order
+ALTER TABLE sales ADD COLUMN order_id BIGINT CONSTRAINT fk_order REFERENCES orders(id);
+
+CREATE INDEX sales_order_id_idx ON sales(order_id);
@@ -0,0 +1,37 @@
+-- Undone orders are recorded here instead of deleting their sales
+CREATE TABLE order_reversals (
+  id BIGINT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+  amount BIGINT NOT NULL CONSTRAINT nonnegative_amount CHECK(amount >= 0),
+  timestamp TIMESTAMPTZ NOT NULL DEFAULT now(),
+  order_id BIGINT NOT NULL,
+  user_id INT NOT NULL,
+
+  CONSTRAINT unique_order
+    UNIQUE(order_id),
+
+  CONSTRAINT fk_order
+    FOREIGN KEY(order_id)
+      REFERENCES orders(id),
+
+  CONSTRAINT fk_user
+    FOREIGN KEY(user_id)
+      REFERENCES users(id)
+);
+
+CREATE FUNCTION order_reversals_update_user_balance() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP IN ('UPDATE', 'DELETE') THEN
+    UPDATE users SET balance = balance - OLD.amount WHERE id = OLD.user_id;
+  END IF;
+
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    UPDATE users SET balance = balance + NEW.amount WHERE id = NEW.user_id;
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_reversals_update_user_balance
+  AFTER INSERT OR UPDATE OR DELETE ON order_reversals
+  FOR EACH ROW EXECUTE FUNCTION order_reversals_update_user_balance();
@@ -0,0 +1,2 @@
+-- How far below zero a user's balance may go, e.g. for board members and bartenders
+ALTER TABLE users ADD COLUMN credit_limit BIGINT NOT NULL DEFAULT 0 CONSTRAINT nonnegative_credit_limit CHECK(credit_limit >= 0);
@@ -0,0 +1,2 @@
+-- NULL means the product is not stock limited
+ALTER TABLE products ADD COLUMN stock INT CONSTRAINT nonnegative_stock CHECK(stock >= 0);
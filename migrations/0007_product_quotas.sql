@@ -0,0 +1,20 @@
+CREATE TYPE quota_period AS ENUM ('day', 'event', 'ever');
+
+-- Limits how many units of a product each user may buy within a period.
+-- 'event' quotas only apply between starts_at and ends_at.
+CREATE TABLE product_quotas (
+  product_id INT PRIMARY KEY NOT NULL,
+  max_amount INT NOT NULL CONSTRAINT nonnegative_max_amount CHECK(max_amount >= 0),
+  period quota_period NOT NULL,
+  starts_at TIMESTAMPTZ,
+  ends_at TIMESTAMPTZ,
+
+  CONSTRAINT event_has_window CHECK(period != 'event' OR (starts_at IS NOT NULL AND ends_at IS NOT NULL AND starts_at < ends_at)),
+
+  CONSTRAINT fk_product
+    FOREIGN KEY(product_id)
+      REFERENCES products(id)
+        ON DELETE CASCADE
+);
+
+CREATE INDEX sales_user_id_product_id_idx ON sales(user_id, product_id);
@@ -0,0 +1,3 @@
+ALTER TABLE products ADD COLUMN age_restricted BOOLEAN NOT NULL DEFAULT false;
+
+ALTER TABLE users ADD COLUMN birth_date DATE;
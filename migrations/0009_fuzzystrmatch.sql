@@ -0,0 +1,2 @@
+-- levenshtein() is used to suggest products when an unknown product is entered
+CREATE EXTENSION IF NOT EXISTS fuzzystrmatch;
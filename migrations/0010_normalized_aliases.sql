@@ -0,0 +1,14 @@
+-- Aliases are matched on a normalized form so they can be typed on keyboards without æ, ø and å.
+-- æ becomes ae, å becomes aa, ø becomes o and all other accents are stripped.
+-- oe is only read as ø in what is typed, so aliases that really contain oe are left alone.
+CREATE EXTENSION IF NOT EXISTS unaccent;
+
+-- unaccent() is only stable as it depends on search_path, naming the dictionary makes it immutable
+CREATE FUNCTION normalize_alias(alias TEXT) RETURNS TEXT AS $$
+  SELECT public.unaccent('public.unaccent', REPLACE(REPLACE(REPLACE(LOWER(alias), 'æ', 'ae'), 'ø', 'o'), 'å', 'aa'))
+$$ LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE;
+
+-- Several aliases can share a normalized form, lookups must report when they point to different products
+ALTER TABLE product_aliases ADD COLUMN alias_normalized TEXT GENERATED ALWAYS AS (normalize_alias(alias_name)) STORED;
+
+CREATE INDEX product_aliases_alias_normalized ON product_aliases(alias_normalized);
@@ -0,0 +1,12 @@
+-- sales.user_id is the user who paid, consumer_id is the user the product was bought for
+ALTER TABLE sales ADD COLUMN consumer_id INT;
+
+UPDATE sales SET consumer_id = user_id;
+
+ALTER TABLE sales ALTER COLUMN consumer_id SET NOT NULL;
+
+ALTER TABLE sales ADD CONSTRAINT fk_consumer FOREIGN KEY(consumer_id) REFERENCES users(id);
+
+-- Quotas limit what a user consumes, not what they pay for, so this replaces the index on who paid
+DROP INDEX sales_user_id_product_id_idx;
+CREATE INDEX sales_consumer_id_product_id_idx ON sales(consumer_id, product_id);
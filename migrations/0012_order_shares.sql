@@ -0,0 +1,61 @@
+-- When a purchase is split between several users the order's sales are charged to the user who made the order.
+-- Every other participant pays their share of it to that user through this table.
+CREATE TABLE order_shares (
+  id BIGINT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+  amount BIGINT NOT NULL CONSTRAINT nonnegative_amount CHECK(amount >= 0),
+  order_id BIGINT NOT NULL,
+  user_id INT NOT NULL,
+
+  CONSTRAINT unique_order_user
+    UNIQUE(order_id, user_id),
+
+  CONSTRAINT fk_order
+    FOREIGN KEY(order_id)
+      REFERENCES orders(id),
+
+  CONSTRAINT fk_user
+    FOREIGN KEY(user_id)
+      REFERENCES users(id)
+);
+
+-- Shares of undone orders are not paid
+CREATE FUNCTION order_shares_update_user_balance() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP IN ('UPDATE', 'DELETE') AND NOT EXISTS(SELECT 1 FROM order_reversals WHERE order_id = OLD.order_id) THEN
+    UPDATE users SET balance = balance + OLD.amount WHERE id = OLD.user_id;
+    UPDATE users SET balance = balance - OLD.amount WHERE id = (SELECT user_id FROM orders WHERE id = OLD.order_id);
+  END IF;
+
+  IF TG_OP IN ('INSERT', 'UPDATE') AND NOT EXISTS(SELECT 1 FROM order_reversals WHERE order_id = NEW.order_id) THEN
+    UPDATE users SET balance = balance - NEW.amount WHERE id = NEW.user_id;
+    UPDATE users SET balance = balance + NEW.amount WHERE id = (SELECT user_id FROM orders WHERE id = NEW.order_id);
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_shares_update_user_balance
+  AFTER INSERT OR UPDATE OR DELETE ON order_shares
+  FOR EACH ROW EXECUTE FUNCTION order_shares_update_user_balance();
+
+-- Undoing an order gives every participant their share back
+CREATE FUNCTION order_reversals_update_share_balances() RETURNS TRIGGER AS $$
+BEGIN
+  IF TG_OP IN ('UPDATE', 'DELETE') THEN
+    UPDATE users SET balance = balance - order_shares.amount FROM order_shares WHERE order_shares.order_id = OLD.order_id AND users.id = order_shares.user_id;
+    UPDATE users SET balance = balance + (SELECT COALESCE(SUM(amount), 0) FROM order_shares WHERE order_id = OLD.order_id) WHERE id = (SELECT user_id FROM orders WHERE id = OLD.order_id);
+  END IF;
+
+  IF TG_OP IN ('INSERT', 'UPDATE') THEN
+    UPDATE users SET balance = balance + order_shares.amount FROM order_shares WHERE order_shares.order_id = NEW.order_id AND users.id = order_shares.user_id;
+    UPDATE users SET balance = balance - (SELECT COALESCE(SUM(amount), 0) FROM order_shares WHERE order_id = NEW.order_id) WHERE id = (SELECT user_id FROM orders WHERE id = NEW.order_id);
+  END IF;
+
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER order_reversals_update_share_balances
+  AFTER INSERT OR UPDATE OR DELETE ON order_reversals
+  FOR EACH ROW EXECUTE FUNCTION order_reversals_update_share_balances();
@@ -0,0 +1,14 @@
+-- Named shortcuts for a list of products, e.g. "morgen" for "kaffe croissant".
+-- Each user has their own macros, which are expanded when they appear in that user's quickbuy.
+CREATE TABLE quickbuy_macros (
+  user_id INT NOT NULL,
+  name VARCHAR(128) NOT NULL CONSTRAINT lower_case_and_no_whitespace_or_colon CHECK(LENGTH(name) != 0 AND name NOT LIKE '% %' AND name NOT LIKE '%:%' AND name = LOWER(name)),
+  expansion VARCHAR(1024) NOT NULL CONSTRAINT nonempty_expansion CHECK(LENGTH(TRIM(expansion)) != 0),
+
+  PRIMARY KEY(user_id, name),
+
+  CONSTRAINT fk_user
+    FOREIGN KEY(user_id)
+      REFERENCES users(id)
+        ON DELETE CASCADE
+);
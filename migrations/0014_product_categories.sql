@@ -0,0 +1,17 @@
+-- Groups products into sections on the menu, sections are shown by ascending sort_order
+CREATE TABLE product_categories (
+  id INT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+  name VARCHAR(128) NOT NULL CONSTRAINT nonempty_name CHECK(LENGTH(name) != 0),
+  sort_order INT NOT NULL DEFAULT 0,
+
+  CONSTRAINT unique_name
+    UNIQUE(name)
+);
+
+-- NULL means the product is uncategorized and shown after all categories
+ALTER TABLE products ADD COLUMN category_id INT;
+
+ALTER TABLE products ADD CONSTRAINT fk_category
+  FOREIGN KEY(category_id)
+    REFERENCES product_categories(id)
+      ON DELETE SET NULL;
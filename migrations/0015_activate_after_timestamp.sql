@@ -0,0 +1,16 @@
+-- Lets seasonal products and news be switched on at a given time, like they can be switched off
+ALTER TABLE products ADD COLUMN activate_after_timestamp TIMESTAMPTZ;
+ALTER TABLE products ADD CONSTRAINT activated_before_deactivated
+  CHECK(activate_after_timestamp IS NULL OR deactivate_after_timestamp IS NULL OR activate_after_timestamp < deactivate_after_timestamp);
+
+ALTER TABLE news ADD COLUMN activate_after_timestamp TIMESTAMPTZ;
+ALTER TABLE news ADD CONSTRAINT activated_before_deactivated
+  CHECK(activate_after_timestamp IS NULL OR deactivate_after_timestamp IS NULL OR activate_after_timestamp < deactivate_after_timestamp);
+
+-- The single definition of when a product or news item is shown and can be bought.
+-- NULL timestamps mean the window is open in that direction.
+CREATE FUNCTION is_active_now(active BOOLEAN, activate_after_timestamp TIMESTAMPTZ, deactivate_after_timestamp TIMESTAMPTZ) RETURNS BOOLEAN AS $$
+  SELECT active
+    AND (activate_after_timestamp IS NULL OR activate_after_timestamp <= now())
+    AND (deactivate_after_timestamp IS NULL OR deactivate_after_timestamp > now())
+$$ LANGUAGE SQL STABLE PARALLEL SAFE;
@@ -0,0 +1,48 @@
+-- Prices are kept with the interval they are valid in, so price changes can be scheduled
+-- in advance and the price of a product at any point in time can be looked up
+CREATE EXTENSION IF NOT EXISTS btree_gist;
+
+CREATE TABLE product_prices (
+  id BIGINT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+  product_id INT NOT NULL,
+  price BIGINT NOT NULL CONSTRAINT nonnegative_price CHECK(price >= 0),
+  valid_from TIMESTAMPTZ NOT NULL DEFAULT now(),
+  -- NULL means the price is valid until further notice
+  valid_until TIMESTAMPTZ,
+  valid_during TSTZRANGE GENERATED ALWAYS AS (tstzrange(valid_from, valid_until)) STORED,
+
+  CONSTRAINT nonempty_interval CHECK(valid_until IS NULL OR valid_from < valid_until),
+
+  CONSTRAINT no_overlapping_prices
+    EXCLUDE USING gist (product_id WITH =, valid_during WITH &&),
+
+  CONSTRAINT fk_product
+    FOREIGN KEY(product_id)
+      REFERENCES products(id)
+        ON DELETE CASCADE
+);
+
+INSERT INTO product_prices(product_id, price, valid_from)
+SELECT id, price, '-infinity'
+FROM products;
+
+ALTER TABLE products DROP COLUMN price;
+
+-- Changes the price of a product from valid_from on. The price in effect at valid_from ends there,
+-- and the new price lasts until the next scheduled price, if any. Returns the id of the new price.
+CREATE FUNCTION schedule_product_price(product_id INT, price BIGINT, valid_from TIMESTAMPTZ) RETURNS BIGINT AS $$
+  UPDATE product_prices
+  SET valid_until = schedule_product_price.valid_from
+  WHERE product_prices.product_id = schedule_product_price.product_id
+    AND product_prices.valid_during @> schedule_product_price.valid_from
+    AND product_prices.valid_from < schedule_product_price.valid_from;
+
+  INSERT INTO product_prices(product_id, price, valid_from, valid_until)
+  VALUES (
+    schedule_product_price.product_id,
+    schedule_product_price.price,
+    schedule_product_price.valid_from,
+    (SELECT MIN(next.valid_from) FROM product_prices AS next WHERE next.product_id = schedule_product_price.product_id AND next.valid_from > schedule_product_price.valid_from)
+  )
+  RETURNING id;
+$$ LANGUAGE SQL;
@@ -0,0 +1,36 @@
+-- The time zone weekdays and times of day are evaluated in, instead of the time zone of the database session.
+-- The server sets stregsystemet.time_zone on its connections from TIME_ZONE, other sessions use Europe/Copenhagen.
+CREATE FUNCTION local_time_zone() RETURNS TEXT
+  LANGUAGE SQL STABLE
+  RETURN COALESCE(NULLIF(current_setting('stregsystemet.time_zone', true), ''), 'Europe/Copenhagen');
+
+-- Temporary prices for a product or every product in a category, e.g. cheaper beer on Fridays 16-18.
+-- Times are local time, see local_time_zone(). When several rules apply the cheapest price wins,
+-- and a rule never makes a product more expensive.
+-- A window is a time of day within a single day (starts_at < ends_at), so a window crossing
+-- midnight, e.g. 22-02, must be written as two rules: 22-24 and 00-02 on the next weekday.
+CREATE TABLE pricing_rules (
+  id INT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+  product_id INT,
+  category_id INT,
+  -- ISO weekday where 1 is Monday, NULL means every day
+  weekday SMALLINT CONSTRAINT iso_weekday CHECK(weekday BETWEEN 1 AND 7),
+  starts_at TIME NOT NULL,
+  ends_at TIME NOT NULL,
+  fixed_price BIGINT CONSTRAINT nonnegative_fixed_price CHECK(fixed_price >= 0),
+  discount_percent INT CONSTRAINT valid_discount_percent CHECK(discount_percent BETWEEN 1 AND 100),
+
+  CONSTRAINT nonempty_window CHECK(starts_at < ends_at),
+  CONSTRAINT one_target CHECK((product_id IS NULL) != (category_id IS NULL)),
+  CONSTRAINT one_adjustment CHECK((fixed_price IS NULL) != (discount_percent IS NULL)),
+
+  CONSTRAINT fk_product
+    FOREIGN KEY(product_id)
+      REFERENCES products(id)
+        ON DELETE CASCADE,
+
+  CONSTRAINT fk_category
+    FOREIGN KEY(category_id)
+      REFERENCES product_categories(id)
+        ON DELETE CASCADE
+);
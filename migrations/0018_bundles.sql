@@ -0,0 +1,41 @@
+-- Offers like "3 sodas for 20 kr" or "coffee + croissant for 15 kr", applied to whole purchases.
+-- Bundles are applied in order of id, each as many times as it makes the purchase cheaper.
+CREATE TABLE bundles (
+  id INT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+  name VARCHAR(128) NOT NULL CONSTRAINT nonempty_name CHECK(LENGTH(name) != 0),
+  price BIGINT NOT NULL CONSTRAINT nonnegative_price CHECK(price >= 0),
+  active BOOLEAN NOT NULL,
+  activate_after_timestamp TIMESTAMPTZ,
+  deactivate_after_timestamp TIMESTAMPTZ,
+
+  CONSTRAINT activated_before_deactivated
+    CHECK(activate_after_timestamp IS NULL OR deactivate_after_timestamp IS NULL OR activate_after_timestamp < deactivate_after_timestamp)
+);
+
+-- Each item is a number of units of a product, or of any products in a category
+CREATE TABLE bundle_items (
+  id INT PRIMARY KEY GENERATED BY DEFAULT AS IDENTITY,
+  bundle_id INT NOT NULL,
+  product_id INT,
+  category_id INT,
+  amount INT NOT NULL CONSTRAINT positive_amount CHECK(amount > 0),
+
+  CONSTRAINT one_target CHECK((product_id IS NULL) != (category_id IS NULL)),
+
+  CONSTRAINT fk_bundle
+    FOREIGN KEY(bundle_id)
+      REFERENCES bundles(id)
+        ON DELETE CASCADE,
+
+  CONSTRAINT fk_product
+    FOREIGN KEY(product_id)
+      REFERENCES products(id)
+        ON DELETE CASCADE,
+
+  CONSTRAINT fk_category
+    FOREIGN KEY(category_id)
+      REFERENCES product_categories(id)
+        ON DELETE CASCADE
+);
+
+CREATE INDEX bundle_items_bundle_id_idx ON bundle_items(bundle_id);
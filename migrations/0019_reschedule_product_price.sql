@@ -0,0 +1,34 @@
+-- Scheduling a price at exactly the time another price starts replaces that price in place,
+-- instead of inserting a second price starting at the same time
+CREATE OR REPLACE FUNCTION schedule_product_price(product_id INT, price BIGINT, valid_from TIMESTAMPTZ) RETURNS BIGINT AS $$
+DECLARE
+  price_id BIGINT;
+BEGIN
+  UPDATE product_prices
+  SET price = schedule_product_price.price
+  WHERE product_prices.product_id = schedule_product_price.product_id
+    AND product_prices.valid_from = schedule_product_price.valid_from
+  RETURNING product_prices.id INTO price_id;
+
+  IF FOUND THEN
+    RETURN price_id;
+  END IF;
+
+  UPDATE product_prices
+  SET valid_until = schedule_product_price.valid_from
+  WHERE product_prices.product_id = schedule_product_price.product_id
+    AND product_prices.valid_during @> schedule_product_price.valid_from
+    AND product_prices.valid_from < schedule_product_price.valid_from;
+
+  INSERT INTO product_prices(product_id, price, valid_from, valid_until)
+  VALUES (
+    schedule_product_price.product_id,
+    schedule_product_price.price,
+    schedule_product_price.valid_from,
+    (SELECT MIN(next.valid_from) FROM product_prices AS next WHERE next.product_id = schedule_product_price.product_id AND next.valid_from > schedule_product_price.valid_from)
+  )
+  RETURNING product_prices.id INTO price_id;
+
+  RETURN price_id;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,6 @@
+-- The quickbuy parser reads "4x4" as four of product 4, "cola*2" as two cola, "@bob:cola" as a cola for bob
+-- and a number as a product id, so aliases written in any of those forms could never be bought by name.
+-- Adding the constraint fails while such aliases exist, they must be renamed before migrating.
+ALTER TABLE product_aliases ADD CONSTRAINT parsable_as_product_name CHECK(
+  alias_name !~ '^[0-9]+(x|$)' AND alias_name NOT LIKE '%*%' AND alias_name NOT LIKE '@%'
+);
@@ -1,9 +1,16 @@
-INSERT INTO products(id, name, price, active, deactivate_after_timestamp)
+INSERT INTO products(id, name, active, deactivate_after_timestamp)
 VALUES 
-  (1, 'Øl',               700 ,  true,  NULL),
-  (2, 'Sodavand',         1200,  true,  NULL),
-  (3, 'Søm',              200,   false, NULL),
-  (4, 'Fytteturs Billet', 30000, true,  '2024-09-01');
+  (1, 'Øl',               true,  NULL),
+  (2, 'Sodavand',         true,  NULL),
+  (3, 'Søm',              false, NULL),
+  (4, 'Fytteturs Billet', true,  '2024-09-01');
+
+INSERT INTO product_prices(product_id, price, valid_from)
+VALUES
+  (1, 700,   '-infinity'),
+  (2, 1200,  '-infinity'),
+  (3, 200,   '-infinity'),
+  (4, 30000, '-infinity');
 
 INSERT INTO product_aliases(alias_name, product_id)
 VALUES
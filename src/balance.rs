@@ -0,0 +1,168 @@
+use sqlx::PgExecutor;
+
+use crate::dso::{streg_cents::StregCents, user::UserId};
+
+#[derive(Debug)]
+pub struct BalanceDrift {
+    pub user_id: UserId,
+    pub username: String,
+    pub stored_balance: StregCents,
+    pub computed_balance: StregCents,
+}
+
+// users.balance is maintained by triggers on sales, deposits, order_reversals and order_shares.
+// This recomputes every balance from history and returns the users where the two disagree.
+pub async fn find_balance_drift<'a, E>(executor: E) -> Result<Vec<BalanceDrift>, sqlx::Error>
+where
+    E: PgExecutor<'a>,
+{
+    sqlx::query_as!(
+        BalanceDrift,
+        r#"
+        SELECT id as "user_id!: UserId", username as "username!", stored_balance as "stored_balance!: StregCents", computed_balance as "computed_balance!: StregCents"
+        FROM (
+            SELECT id, username, balance as stored_balance, (
+              (SELECT COALESCE(SUM(amount), 0) FROM deposits WHERE user_id = users.id)
+              - (SELECT COALESCE(SUM(price), 0) FROM sales WHERE user_id = users.id)
+              + (SELECT COALESCE(SUM(amount), 0) FROM order_reversals WHERE user_id = users.id)
+              - (SELECT COALESCE(SUM(amount), 0) FROM order_shares WHERE user_id = users.id AND NOT EXISTS(SELECT 1 FROM order_reversals WHERE order_id = order_shares.order_id))
+              + (SELECT COALESCE(SUM(amount), 0) FROM order_shares JOIN orders ON orders.id = order_shares.order_id WHERE orders.user_id = users.id AND NOT EXISTS(SELECT 1 FROM order_reversals WHERE order_id = order_shares.order_id))
+            )::bigint as computed_balance
+            FROM users
+        ) balances
+        WHERE stored_balance <> computed_balance
+        ORDER BY id
+        "#
+    )
+    .fetch_all(executor)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+
+    #[sqlx::test(fixtures(
+        "../fixtures/users.sql",
+        "../fixtures/products.sql",
+        "../fixtures/deposits.sql"
+    ))]
+    async fn no_drift_after_deposits_and_sales(pool: PgPool) {
+        sqlx::query!(
+            "INSERT INTO sales(price, product_id, user_id, consumer_id) VALUES (700, 1, 1, 1), (1200, 2, 1, 1)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let drift = find_balance_drift(&pool).await.unwrap();
+
+        assert!(drift.is_empty());
+    }
+
+    #[sqlx::test(fixtures(
+        "../fixtures/users.sql",
+        "../fixtures/products.sql",
+        "../fixtures/deposits.sql"
+    ))]
+    async fn balance_follows_deleted_sales(pool: PgPool) {
+        sqlx::query!(
+            "INSERT INTO sales(price, product_id, user_id, consumer_id) VALUES (700, 1, 1, 1)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!("DELETE FROM sales")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let balance = sqlx::query_scalar!(
+            r#"SELECT balance as "balance: StregCents" FROM users WHERE id = 1"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        assert_eq!(balance.to_string(), "100.00");
+        assert!(find_balance_drift(&pool).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(fixtures("../fixtures/users.sql", "../fixtures/deposits.sql"))]
+    async fn drift_is_reported(pool: PgPool) {
+        sqlx::query!("UPDATE users SET balance = 42 WHERE id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let drift = find_balance_drift(&pool).await.unwrap();
+
+        assert_eq!(drift.len(), 1);
+        assert_eq!(drift[0].username, "test_user");
+        assert_eq!(drift[0].stored_balance.to_string(), "0.42");
+        assert_eq!(drift[0].computed_balance.to_string(), "100.00");
+    }
+
+    #[sqlx::test(fixtures(
+        "../fixtures/users.sql",
+        "../fixtures/products.sql",
+        "../fixtures/deposits.sql"
+    ))]
+    async fn no_drift_after_order_reversal(pool: PgPool) {
+        sqlx::query!("INSERT INTO orders(id, user_id) VALUES (1, 1)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "INSERT INTO sales(price, product_id, user_id, consumer_id, order_id) VALUES (700, 1, 1, 1, 1)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!("INSERT INTO order_reversals(amount, order_id, user_id) VALUES (700, 1, 1)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(find_balance_drift(&pool).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(fixtures(
+        "../fixtures/users.sql",
+        "../fixtures/products.sql",
+        "../fixtures/deposits.sql"
+    ))]
+    async fn no_drift_after_split_order_and_reversal(pool: PgPool) {
+        sqlx::query!("INSERT INTO orders(id, user_id) VALUES (1, 1)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query!(
+            "INSERT INTO sales(price, product_id, user_id, consumer_id, order_id) VALUES (700, 1, 1, 1, 1)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!("INSERT INTO order_shares(amount, order_id, user_id) VALUES (350, 1, 2)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(find_balance_drift(&pool).await.unwrap().is_empty());
+
+        sqlx::query!("INSERT INTO order_reversals(amount, order_id, user_id) VALUES (700, 1, 1)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(find_balance_drift(&pool).await.unwrap().is_empty());
+        let balances =
+            sqlx::query_scalar!("SELECT balance FROM users WHERE id IN (1, 2) ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(balances, [10000, 0]);
+    }
+}
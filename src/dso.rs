@@ -1,3 +1,6 @@
+pub mod category;
+pub mod order;
 pub mod product;
+pub mod quota;
 pub mod streg_cents;
 pub mod user;
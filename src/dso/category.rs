@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, sqlx::Type, PartialEq, Eq, Clone, Copy, Hash)]
+#[sqlx(transparent)]
+pub struct CategoryId(i32);
+
+impl CategoryId {
+    // Category ids only come from the database, tests need to make their own
+    #[cfg(test)]
+    pub fn new(id: i32) -> CategoryId {
+        CategoryId(id)
+    }
+}
@@ -0,0 +1,5 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, sqlx::Type, PartialEq, Eq, Clone, Copy)]
+#[sqlx(transparent)]
+pub struct OrderId(i64);
@@ -0,0 +1,9 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, sqlx::Type, PartialEq, Eq, Clone, Copy)]
+#[sqlx(type_name = "quota_period", rename_all = "lowercase")]
+pub enum QuotaPeriod {
+    Day,
+    Event,
+    Ever,
+}
@@ -7,17 +7,18 @@ use std::{
 use serde::{Deserialize, Serialize};
 
 #[derive(
-    Deserialize, Serialize, Debug, sqlx::Type, PartialEq, Eq, PartialOrd, Ord, Clone, Copy,
+    Deserialize, Serialize, Debug, sqlx::Type, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default,
 )]
 #[sqlx(transparent)]
 pub struct StregCents(i64);
 
 impl Display for StregCents {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let dollars = self.0 / 100;
-        let cents = self.0 % 100;
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let dollars = self.0.unsigned_abs() / 100;
+        let cents = self.0.unsigned_abs() % 100;
 
-        write!(f, "{}.{:02}", dollars, cents)
+        write!(f, "{}{}.{:02}", sign, dollars, cents)
     }
 }
 
@@ -46,6 +47,59 @@ impl Mul<NonZeroU32> for StregCents {
     }
 }
 
+impl StregCents {
+    // Outside this module stregcents only come from the database or arithmetic, tests need to make their own
+    #[cfg(test)]
+    pub fn from_cents(cents: i64) -> StregCents {
+        StregCents(cents)
+    }
+
+    // Splits into parts that differ by at most one cent, the leftover cents go to the first parts
+    pub fn split_evenly(self, parts: NonZeroU32) -> Vec<StregCents> {
+        let parts = i64::from(parts.get());
+        let share = self.0.div_euclid(parts);
+        let remainder = self.0.rem_euclid(parts);
+
+        (0..parts)
+            .map(|i| StregCents(share + i64::from(i < remainder)))
+            .collect()
+    }
+
+    // Splits in proportion to the weights, rounding down and giving the leftover cents to the first parts.
+    // The parts always add up to self. All weights being zero is treated as equal weights.
+    pub fn split_proportionally(self, weights: &[StregCents]) -> Vec<StregCents> {
+        let total_weight = weights.iter().map(|w| i128::from(w.0)).sum::<i128>();
+        if total_weight == 0 {
+            return match NonZeroU32::new(weights.len() as u32) {
+                Some(parts) => self.split_evenly(parts),
+                None => vec![],
+            };
+        }
+
+        let mut parts = weights
+            .iter()
+            .map(|w| (i128::from(self.0) * i128::from(w.0)).div_euclid(total_weight))
+            .collect::<Vec<i128>>();
+        let remainder = i128::from(self.0) - parts.iter().sum::<i128>();
+        for part in parts.iter_mut().take(remainder as usize) {
+            *part += 1;
+        }
+
+        parts
+            .into_iter()
+            .map(|p| StregCents(i64::try_from(p).expect("a part is never larger than the whole")))
+            .collect()
+    }
+
+    // Rounds half a cent up, so a discount always gives the same price regardless of how it is computed
+    pub fn discounted_by_percent(self, percent: u8) -> StregCents {
+        let kept_percent = 100 - i128::from(percent.min(100));
+        let discounted = (i128::from(self.0) * kept_percent + 50).div_euclid(100);
+
+        StregCents(i64::try_from(discounted).expect("a discount can't increase the magnitude"))
+    }
+}
+
 pub fn stregcents_sum<I>(mut iterator: I) -> Option<StregCents>
 where
     I: Iterator<Item = Option<StregCents>>,
@@ -70,4 +124,60 @@ mod tests {
         assert_eq!(streg_cents_zero_cents.to_string(), "8.00");
         assert_eq!(streg_cents_zero.to_string(), "0.00");
     }
+
+    #[test]
+    fn split_evenly() {
+        let shares = StregCents(1000).split_evenly(NonZeroU32::new(3).unwrap());
+
+        assert_eq!(shares, [StregCents(334), StregCents(333), StregCents(333)]);
+    }
+
+    #[test]
+    fn split_evenly_without_remainder() {
+        let shares = StregCents(900).split_evenly(NonZeroU32::new(3).unwrap());
+
+        assert_eq!(shares, [StregCents(300), StregCents(300), StregCents(300)]);
+    }
+
+    #[test]
+    fn split_proportionally() {
+        let parts = StregCents(2000).split_proportionally(&[
+            StregCents(1100),
+            StregCents(1100),
+            StregCents(1100),
+        ]);
+
+        assert_eq!(parts, [StregCents(667), StregCents(667), StregCents(666)]);
+    }
+
+    #[test]
+    fn split_proportionally_uneven_weights() {
+        let parts = StregCents(1500).split_proportionally(&[StregCents(1200), StregCents(600)]);
+
+        assert_eq!(parts, [StregCents(1000), StregCents(500)]);
+    }
+
+    #[test]
+    fn discounted_by_percent() {
+        assert_eq!(StregCents(1100).discounted_by_percent(15), StregCents(935));
+        assert_eq!(StregCents(1000).discounted_by_percent(0), StregCents(1000));
+        assert_eq!(StregCents(1000).discounted_by_percent(100), StregCents(0));
+    }
+
+    #[test]
+    fn discounted_by_percent_rounds_half_up() {
+        // 49.5 and 0.5 cents are rounded up, 49.17 cents is rounded down
+        assert_eq!(StregCents(150).discounted_by_percent(67), StregCents(50));
+        assert_eq!(StregCents(1).discounted_by_percent(50), StregCents(1));
+        assert_eq!(StregCents(149).discounted_by_percent(67), StregCents(49));
+    }
+
+    #[test]
+    fn negative_to_string() {
+        let streg_cents = StregCents(-750);
+        let streg_cents_only_cents = StregCents(-5);
+
+        assert_eq!(streg_cents.to_string(), "-7.50");
+        assert_eq!(streg_cents_only_cents.to_string(), "-0.05");
+    }
 }
@@ -1,4 +1,7 @@
+mod balance;
 mod dso;
+mod menu;
+mod pricing;
 mod protocol;
 mod quickbuy;
 mod responses;
@@ -19,23 +22,30 @@ use axum::{
     BoxError, Json, Router,
 };
 
+use balance::find_balance_drift;
 use dotenv::dotenv;
-use dso::{product::ProductId, streg_cents::StregCents};
+use dso::streg_cents::StregCents;
 
 use http_body_util::BodyExt;
 use httpdate::HttpDate;
 use lru::LruCache;
+use menu::{fetch_active_product_categories, fetch_active_products};
 use protocol::{
-    buy_request::{BuyError, BuyRequest, BuyResponse},
+    buy_request::{BuyError, BuyRequest, BuyResponse, PreviewResponse, UndoRequest},
+    macros::{DeleteMacroRequest, MacroError, MacrosResponse, QuickBuyMacro, SetMacroRequest},
     products::active_products_response::DatabaseError,
     users::{UserInfoError, UserInfoResponse, UsernameRequest},
 };
 use protocol::{
     news::ActiveNewsResponse,
-    products::active_products_response::{ActiveProduct, ActiveProductsResponse},
+    products::active_products_response::{ActiveProductCategoriesResponse, ActiveProductsResponse},
 };
 use quickbuy::{
-    executor::{execute_multi_buy_query, username_exists},
+    executor::{
+        execute_multi_buy_query, execute_repeat_query, execute_undo_query,
+        get_username_suggestions, preview_multi_buy_query, preview_repeat_query, username_exists,
+    },
+    macros::{delete_user_macro, get_user_macros, set_user_macro},
     parser::{parse_quickbuy_query, QuickBuyType},
 };
 use rand::Rng;
@@ -44,12 +54,16 @@ use sqlx::{postgres::PgPoolOptions, PgPool};
 use tokio::{net::TcpListener, signal, sync::Mutex};
 use tower::{timeout::TimeoutLayer, ServiceBuilder};
 use tower_http::{services::ServeDir, trace::TraceLayer};
+use tracing::warn;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 lazy_static! {
     static ref START_TIME: String = HttpDate::from(std::time::SystemTime::now()).to_string();
 }
 
+const DEFAULT_UNDO_WINDOW: Duration = Duration::from_secs(60);
+const DEFAULT_TIME_ZONE: &str = "Europe/Copenhagen";
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenv().ok();
@@ -65,16 +79,56 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let db_connection_string = std::env::var("DATABASE_URL")?;
 
+    let undo_window = match std::env::var("UNDO_WINDOW_SECONDS") {
+        Ok(undo_window_seconds) => Duration::from_secs(undo_window_seconds.parse()?),
+        Err(_) => DEFAULT_UNDO_WINDOW,
+    };
+
+    let suggest_usernames = match std::env::var("SUGGEST_USERNAMES") {
+        Ok(suggest_usernames) => suggest_usernames.parse()?,
+        Err(_) => false,
+    };
+
+    let time_zone = std::env::var("TIME_ZONE").unwrap_or_else(|_| DEFAULT_TIME_ZONE.to_string());
+
     let pool = PgPoolOptions::new()
         .max_connections(5)
+        .after_connect({
+            let time_zone = time_zone.clone();
+            move |connection, _| {
+                let time_zone = time_zone.clone();
+                Box::pin(async move {
+                    sqlx::query!(
+                        "SELECT set_config('stregsystemet.time_zone', $1, false)",
+                        time_zone
+                    )
+                    .fetch_one(connection)
+                    .await?;
+                    Ok(())
+                })
+            }
+        })
         .connect(&db_connection_string)
         .await?;
 
     sqlx::migrate!("./migrations").run(&pool).await?;
 
+    // Fails on time zones postgres doesn't know, rather than on the first purchase
+    sqlx::query!("SELECT now() AT TIME ZONE $1 as now", time_zone)
+        .fetch_one(&pool)
+        .await?;
+
+    for drift in find_balance_drift(&pool).await? {
+        warn!(
+            target: "stregsystemet",
+            "balance of user {} ({:?}) is {} kr but their sales and deposits add up to {} kr",
+            drift.username, drift.user_id, drift.stored_balance, drift.computed_balance
+        );
+    }
+
     let listener = TcpListener::bind("0.0.0.0:8080").await?;
 
-    axum::serve(listener, app(pool))
+    axum::serve(listener, app(pool, undo_window, suggest_usernames))
         .with_graceful_shutdown(shutdown_signal())
         .await?;
 
@@ -86,21 +140,40 @@ type IdempotencyCache = Arc<Mutex<LruCache<(Method, Uri, String), (Parts, Bytes)
 struct MyState {
     pool: PgPool,
     idempotency_cache: IdempotencyCache,
+    undo_window: Duration,
+    suggest_usernames: bool,
 }
 
-fn app(pool: PgPool) -> Router {
+fn app(pool: PgPool, undo_window: Duration, suggest_usernames: bool) -> Router {
     let state = MyState {
         pool,
         idempotency_cache: Arc::new(Mutex::new(LruCache::new(NonZeroUsize::new(256).unwrap()))),
+        undo_window,
+        suggest_usernames,
     };
 
     let router = Router::new()
         .route("/", get(index_handler))
         .route("/menu/", get(menu_handler))
         .route("/api/products/active", get(get_active_products))
+        .route(
+            "/api/products/active/categories",
+            get(get_active_product_categories),
+        )
         .route("/api/purchase/quickbuy", post(quickbuy_handler))
+        .route(
+            "/api/purchase/quickbuy/preview",
+            post(quickbuy_preview_handler),
+        )
+        .route("/api/purchase/undo", post(undo_handler))
         .route("/api/news/active", get(get_active_news_handler))
         .route("/api/users/info", get(get_users_info_handler))
+        .route(
+            "/api/users/macros",
+            get(get_macros_handler)
+                .post(set_macro_handler)
+                .delete(delete_macro_handler),
+        )
         .nest_service(
             "/static",
             ServiceBuilder::new()
@@ -187,8 +260,8 @@ async fn idempotency_key_handller(
 async fn _inject_random_faults(request: Request, next: Next) -> Response {
     // If we don't put this in a function it wont compile :)
     fn get_random() -> bool {
-        let mut rng = rand::thread_rng();
-        rng.gen_ratio(1, 2)
+        let mut rng = rand::rng();
+        rng.random_ratio(1, 2)
     }
 
     let is_api_call = request.uri().path().starts_with("/api");
@@ -268,34 +341,25 @@ async fn get_active_products(
     State(state): State<MyState>,
 ) -> ResultJson<ActiveProductsResponse, DatabaseError> {
     async {
-        let products = sqlx::query!(
-            r#"
-            SELECT products.id as "id: ProductId", products.name, products.price as "price: StregCents", STRING_AGG(product_aliases.alias_name, ' ') as aliases
-            -- ' ' is an illegal character in aliases so it can be used as a separator
-            FROM products
-            LEFT JOIN product_aliases
-            ON products.id=product_aliases.product_id
-            WHERE products.active=true AND (products.deactivate_after_timestamp IS NULL OR products.deactivate_after_timestamp > now())
-            GROUP BY products.id, products.name, products.price
-            ORDER BY products.id
-            "#)
-            .fetch_all(&state.pool)
-            .await?;
-
-        let active_products = products
-            .into_iter()
-            .map(|p| ActiveProduct {
-                id: p.id,
-                name: p.name,
-                price: p.price.to_string(),
-                aliases: p.aliases.map(|a| a.split(' ').map(|a| a.to_string()).collect()).unwrap_or_default(),
-            })
-            .collect();
-
         Ok(ActiveProductsResponse {
-            products: active_products,
+            products: fetch_active_products(&state.pool).await?,
         })
-    }.await.into()
+    }
+    .await
+    .into()
+}
+
+#[debug_handler]
+async fn get_active_product_categories(
+    State(state): State<MyState>,
+) -> ResultJson<ActiveProductCategoriesResponse, DatabaseError> {
+    async {
+        Ok(ActiveProductCategoriesResponse {
+            categories: fetch_active_product_categories(&state.pool).await?,
+        })
+    }
+    .await
+    .into()
 }
 
 #[debug_handler]
@@ -307,17 +371,45 @@ async fn quickbuy_handler(
         let quickbuy_type = parse_quickbuy_query(&buy_request.quickbuy)?;
         match quickbuy_type {
             QuickBuyType::Username { username } => {
-                username_exists(&username, &state.pool).await?;
+                username_exists(&username, state.suggest_usernames, &state.pool).await?;
                 Ok(BuyResponse::Username { username })
             }
-            QuickBuyType::MultiBuy { username, products } => {
-                let (bought_products, product_price_sum, new_user_balance) =
-                    execute_multi_buy_query(&username, &products, &state.pool).await?;
+            QuickBuyType::MultiBuy {
+                username,
+                split_with,
+                products,
+            } => {
+                let multi_buy_result = execute_multi_buy_query(
+                    &username,
+                    &split_with,
+                    &products,
+                    state.suggest_usernames,
+                    &state.pool,
+                )
+                .await?;
                 Ok(BuyResponse::MultiBuy {
                     username,
-                    bought_products,
-                    product_price_sum: product_price_sum.to_string(),
-                    new_user_balance: new_user_balance.to_string(),
+                    order_id: multi_buy_result.order_id,
+                    bought_products: multi_buy_result.bought_products,
+                    product_price_sum: multi_buy_result.product_price_sum.to_string(),
+                    new_user_balance: multi_buy_result.new_user_balance.to_string(),
+                    shares: multi_buy_result.shares,
+                    discounts: multi_buy_result.discounts,
+                })
+            }
+            QuickBuyType::Undo { username } => undo_last_purchase(username, &state).await,
+            QuickBuyType::Repeat { username } => {
+                let (repeated_order_id, multi_buy_result) =
+                    execute_repeat_query(&username, state.suggest_usernames, &state.pool).await?;
+                Ok(BuyResponse::Repeat {
+                    username,
+                    repeated_order_id,
+                    order_id: multi_buy_result.order_id,
+                    bought_products: multi_buy_result.bought_products,
+                    product_price_sum: multi_buy_result.product_price_sum.to_string(),
+                    new_user_balance: multi_buy_result.new_user_balance.to_string(),
+                    shares: multi_buy_result.shares,
+                    discounts: multi_buy_result.discounts,
                 })
             }
         }
@@ -326,6 +418,89 @@ async fn quickbuy_handler(
     .into()
 }
 
+#[debug_handler]
+async fn quickbuy_preview_handler(
+    State(state): State<MyState>,
+    Json(buy_request): Json<BuyRequest>,
+) -> ResultJson<PreviewResponse, BuyError> {
+    async {
+        let quickbuy_type = parse_quickbuy_query(&buy_request.quickbuy)?;
+        match quickbuy_type {
+            QuickBuyType::Username { username } => {
+                username_exists(&username, state.suggest_usernames, &state.pool).await?;
+                Ok(PreviewResponse::Username { username })
+            }
+            QuickBuyType::MultiBuy {
+                username,
+                split_with,
+                products,
+            } => {
+                let preview = preview_multi_buy_query(
+                    &username,
+                    &split_with,
+                    &products,
+                    state.suggest_usernames,
+                    &state.pool,
+                )
+                .await?;
+                Ok(PreviewResponse::MultiBuy {
+                    username,
+                    products: preview.products,
+                    product_price_sum: preview.product_price_sum.to_string(),
+                    new_user_balance: preview.new_user_balance.to_string(),
+                    shares: preview.shares,
+                    discounts: preview.discounts,
+                })
+            }
+            QuickBuyType::Undo { username } => {
+                username_exists(&username, state.suggest_usernames, &state.pool).await?;
+                Ok(PreviewResponse::Undo { username })
+            }
+            QuickBuyType::Repeat { username } => {
+                let (repeated_order_id, preview) =
+                    preview_repeat_query(&username, state.suggest_usernames, &state.pool).await?;
+                Ok(PreviewResponse::Repeat {
+                    username,
+                    repeated_order_id,
+                    products: preview.products,
+                    product_price_sum: preview.product_price_sum.to_string(),
+                    new_user_balance: preview.new_user_balance.to_string(),
+                    shares: preview.shares,
+                    discounts: preview.discounts,
+                })
+            }
+        }
+    }
+    .await
+    .into()
+}
+
+#[debug_handler]
+async fn undo_handler(
+    State(state): State<MyState>,
+    Json(undo_request): Json<UndoRequest>,
+) -> ResultJson<BuyResponse, BuyError> {
+    undo_last_purchase(undo_request.username, &state)
+        .await
+        .into()
+}
+
+async fn undo_last_purchase(username: String, state: &MyState) -> Result<BuyResponse, BuyError> {
+    let (order_id, refunded_price_sum, new_user_balance) = execute_undo_query(
+        &username,
+        state.undo_window,
+        state.suggest_usernames,
+        &state.pool,
+    )
+    .await?;
+    Ok(BuyResponse::Undo {
+        username,
+        order_id,
+        refunded_price_sum: refunded_price_sum.to_string(),
+        new_user_balance: new_user_balance.to_string(),
+    })
+}
+
 #[debug_handler]
 async fn get_active_news_handler(
     State(state): State<MyState>,
@@ -335,15 +510,14 @@ async fn get_active_news_handler(
             r#"
             SELECT content
             FROM news
-            WHERE active=true AND (deactivate_after_timestamp IS NULL OR deactivate_after_timestamp > now())
+            WHERE is_active_now(active, activate_after_timestamp, deactivate_after_timestamp)
             ORDER BY id
-            "#)
-            .fetch_all(&state.pool)
-            .await?;
+            "#
+        )
+        .fetch_all(&state.pool)
+        .await?;
 
-        Ok(ActiveNewsResponse {
-            news,
-        })
+        Ok(ActiveNewsResponse { news })
     }
     .await
     .into()
@@ -357,18 +531,83 @@ async fn get_users_info_handler(
     async {
         let user_info = sqlx::query!(
             r#"
-            SELECT id, username, email, ((SELECT COALESCE(SUM(amount), 0) FROM deposits WHERE user_id = users.id) - (SELECT COALESCE(SUM(price), 0) FROM sales WHERE user_id = users.id))::bigint as "balance!: StregCents"
+            SELECT id, username, email, balance as "balance: StregCents"
             FROM users
             WHERE LOWER(username) = LOWER($1)
             "#,
-            username_request.username)
-            .fetch_optional(&state.pool)
-            .await?;
+            username_request.username
+        )
+        .fetch_optional(&state.pool)
+        .await?;
 
-        let user_info = user_info.ok_or(UserInfoError::InvalidUsername(username_request.username))?;
-        let user_info = UserInfoResponse { username: user_info.username, first_name: "SAVE FIRST NAME".to_string(), last_name: "SAVE LAST NAME".to_string(), email: user_info.email, balance: user_info.balance.to_string() };
+        let Some(user_info) = user_info else {
+            let suggestions = if state.suggest_usernames {
+                get_username_suggestions(&username_request.username, &state.pool).await?
+            } else {
+                vec![]
+            };
+            return Err(UserInfoError::InvalidUsername {
+                username: username_request.username,
+                suggestions,
+            });
+        };
+        let user_info = UserInfoResponse {
+            username: user_info.username,
+            first_name: "SAVE FIRST NAME".to_string(),
+            last_name: "SAVE LAST NAME".to_string(),
+            email: user_info.email,
+            balance: user_info.balance.to_string(),
+        };
         Ok(user_info)
-    }.await.into()
+    }
+    .await
+    .into()
+}
+
+#[debug_handler]
+async fn get_macros_handler(
+    State(state): State<MyState>,
+    Query(username_request): Query<UsernameRequest>,
+) -> ResultJson<MacrosResponse, MacroError> {
+    get_user_macros(
+        &username_request.username,
+        state.suggest_usernames,
+        &state.pool,
+    )
+    .await
+    .map(|macros| MacrosResponse { macros })
+    .into()
+}
+
+#[debug_handler]
+async fn set_macro_handler(
+    State(state): State<MyState>,
+    Json(macro_request): Json<SetMacroRequest>,
+) -> ResultJson<QuickBuyMacro, MacroError> {
+    set_user_macro(
+        &macro_request.username,
+        &macro_request.name,
+        &macro_request.expansion,
+        state.suggest_usernames,
+        &state.pool,
+    )
+    .await
+    .into()
+}
+
+#[debug_handler]
+async fn delete_macro_handler(
+    State(state): State<MyState>,
+    Json(macro_request): Json<DeleteMacroRequest>,
+) -> ResultJson<QuickBuyMacro, MacroError> {
+    delete_user_macro(
+        &macro_request.username,
+        &macro_request.name,
+        state.suggest_usernames,
+        &state.pool,
+    )
+    .await
+    .into()
 }
 
 #[derive(Template)]
@@ -0,0 +1,189 @@
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::{
+    dso::{category::CategoryId, product::ProductId, quota::QuotaPeriod, streg_cents::StregCents},
+    pricing::get_discounted_prices,
+    protocol::products::active_products_response::{
+        ActiveProduct, ActiveProductCategory, ActiveProductQuota,
+    },
+};
+
+pub async fn fetch_active_products(pool: &PgPool) -> Result<Vec<ActiveProduct>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let products = get_active_products(&mut transaction).await?;
+    transaction.commit().await?;
+
+    Ok(products)
+}
+
+pub async fn fetch_active_product_categories(
+    pool: &PgPool,
+) -> Result<Vec<ActiveProductCategory>, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    // Both reads must see the same snapshot, or a product could be moved to a category that isn't listed
+    sqlx::query!("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ")
+        .execute(&mut *transaction)
+        .await?;
+
+    let categories = sqlx::query!(
+        r#"
+        SELECT id as "id: CategoryId", name
+        FROM product_categories
+        ORDER BY sort_order, name
+        "#
+    )
+    .fetch_all(&mut *transaction)
+    .await?
+    .into_iter()
+    .map(|c| (c.id, c.name))
+    .collect::<Vec<_>>();
+
+    let products = get_active_products(&mut transaction).await?;
+    transaction.commit().await?;
+
+    Ok(group_by_category(categories, products))
+}
+
+// Groups the products in the order of the categories, followed by the uncategorized products.
+// Categories without products are left out.
+fn group_by_category(
+    categories: Vec<(CategoryId, String)>,
+    mut products: Vec<ActiveProduct>,
+) -> Vec<ActiveProductCategory> {
+    let mut groups = vec![];
+    for (category_id, name) in categories
+        .into_iter()
+        .map(|(id, name)| (Some(id), Some(name)))
+        .chain([(None, None)])
+    {
+        let (category_products, rest) = products
+            .into_iter()
+            .partition(|p| p.category_id == category_id);
+        products = rest;
+
+        if !category_products.is_empty() {
+            groups.push(ActiveProductCategory {
+                name,
+                products: category_products,
+            });
+        }
+    }
+
+    groups
+}
+
+// The products for sale right now, in id order
+async fn get_active_products(
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Vec<ActiveProduct>, sqlx::Error> {
+    let products = sqlx::query!(
+        r#"
+        SELECT products.id as "id: ProductId", products.name, product_prices.price as "price: StregCents", products.stock, products.age_restricted, products.category_id as "category_id: CategoryId", product_categories.name as "category?", product_quotas.max_amount as "quota_max_amount?", product_quotas.period as "quota_period?: QuotaPeriod", STRING_AGG(product_aliases.alias_name, ' ') as aliases
+        -- ' ' is an illegal character in aliases so it can be used as a separator
+        FROM products
+        LEFT JOIN product_aliases
+        ON products.id=product_aliases.product_id
+        JOIN product_prices
+        ON products.id=product_prices.product_id AND product_prices.valid_during @> now()
+        LEFT JOIN product_categories
+        ON products.category_id=product_categories.id
+        LEFT JOIN product_quotas
+        ON products.id=product_quotas.product_id AND (product_quotas.period != 'event' OR (product_quotas.starts_at <= now() AND product_quotas.ends_at > now()))
+        WHERE is_active_now(products.active, products.activate_after_timestamp, products.deactivate_after_timestamp)
+        GROUP BY products.id, products.name, product_prices.price, products.stock, products.age_restricted, product_categories.name, product_quotas.max_amount, product_quotas.period
+        ORDER BY products.id
+        "#)
+        .fetch_all(&mut **transaction)
+        .await?;
+
+    let discounted_prices = get_discounted_prices(
+        &products.iter().map(|p| (p.id, p.price)).collect::<Vec<_>>(),
+        &mut **transaction,
+    )
+    .await?;
+
+    Ok(products
+        .into_iter()
+        .zip(discounted_prices)
+        .map(|(p, price)| ActiveProduct {
+            id: p.id,
+            name: p.name,
+            price: price.to_string(),
+            original_price: p.price.to_string(),
+            category_id: p.category_id,
+            category: p.category,
+            sold_out: p.stock == Some(0),
+            age_restricted: p.age_restricted,
+            quota: p
+                .quota_max_amount
+                .zip(p.quota_period)
+                .map(|(max_amount, period)| ActiveProductQuota { max_amount, period }),
+            aliases: p
+                .aliases
+                .map(|a| a.split(' ').map(|a| a.to_string()).collect())
+                .unwrap_or_default(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(fixtures("../fixtures/products.sql", "../fixtures/product_categories.sql"))]
+    async fn active_product_categories(pool: PgPool) {
+        sqlx::query!(
+            r#"
+            INSERT INTO product_categories(id, name, sort_order)
+            VALUES (2, 'Øl', 0), (3, 'Kaffe', 1), (4, 'Tom', 0)
+            "#
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "UPDATE products SET category_id = CASE id WHEN 11 THEN 2 ELSE 3 END WHERE id IN (11, 12)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let categories = fetch_active_product_categories(&pool).await.unwrap();
+
+        let categories = categories
+            .iter()
+            .map(|c| {
+                (
+                    c.name.as_deref(),
+                    c.products
+                        .iter()
+                        .map(|p| p.name.as_str())
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            categories,
+            [
+                (Some("Øl"), vec!["Øl"]),
+                // Categories with the same sort order are sorted by name
+                (Some("Kaffe"), vec!["Café"]),
+                (Some("Sodavand"), vec!["Sodavand", "Sødavand"]),
+                (
+                    None,
+                    vec![
+                        "Enabled",
+                        "No aliases",
+                        "Expensive",
+                        "Overflow trigger",
+                        "Limited",
+                        "Sold out",
+                        "Rationed",
+                        "Restricted",
+                        "Activated by Timestamp"
+                    ]
+                )
+            ]
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use sqlx::PgExecutor;
+
+use crate::dso::{product::ProductId, streg_cents::StregCents};
+
+// The prices of the products after applying the pricing rules in effect now, in the same order.
+// now() is the start of the transaction, so a purchase is priced at the time its sales are recorded.
+// Weekdays and times of day are local time, see local_time_zone(). Windows can't cross midnight.
+pub async fn get_discounted_prices<'a, E>(
+    prices: &[(ProductId, StregCents)],
+    executor: E,
+) -> Result<Vec<StregCents>, sqlx::Error>
+where
+    E: PgExecutor<'a>,
+{
+    let product_ids = prices
+        .iter()
+        .map(|&(product_id, _)| product_id)
+        .collect::<Vec<ProductId>>();
+
+    let rules = sqlx::query!(
+        r#"
+        SELECT products.id as "product_id: ProductId", pricing_rules.fixed_price as "fixed_price: StregCents", pricing_rules.discount_percent
+        FROM products
+        JOIN pricing_rules
+        ON pricing_rules.product_id = products.id OR pricing_rules.category_id = products.category_id
+        WHERE products.id = ANY($1)
+          AND (pricing_rules.weekday IS NULL OR pricing_rules.weekday = EXTRACT(ISODOW FROM now() AT TIME ZONE local_time_zone()))
+          AND pricing_rules.starts_at <= (now() AT TIME ZONE local_time_zone())::time AND pricing_rules.ends_at > (now() AT TIME ZONE local_time_zone())::time
+        "#,
+        &product_ids as &[ProductId]
+    )
+    .fetch_all(executor)
+    .await?;
+
+    let mut rules_by_product: HashMap<ProductId, Vec<PriceAdjustment>> = HashMap::new();
+    for rule in rules {
+        let adjustment = match (rule.fixed_price, rule.discount_percent) {
+            (Some(fixed_price), _) => PriceAdjustment::FixedPrice(fixed_price),
+            (None, Some(discount_percent)) => PriceAdjustment::DiscountPercent(
+                u8::try_from(discount_percent).expect("discounts are at most 100 percent"),
+            ),
+            (None, None) => unreachable!("a pricing rule has exactly one adjustment"),
+        };
+        rules_by_product
+            .entry(rule.product_id)
+            .or_default()
+            .push(adjustment);
+    }
+
+    Ok(prices
+        .iter()
+        .map(|(product_id, price)| {
+            rules_by_product
+                .get(product_id)
+                .into_iter()
+                .flatten()
+                .map(|adjustment| adjustment.apply(*price))
+                .fold(*price, StregCents::min)
+        })
+        .collect())
+}
+
+enum PriceAdjustment {
+    FixedPrice(StregCents),
+    DiscountPercent(u8),
+}
+
+impl PriceAdjustment {
+    fn apply(&self, price: StregCents) -> StregCents {
+        match self {
+            PriceAdjustment::FixedPrice(fixed_price) => *fixed_price,
+            PriceAdjustment::DiscountPercent(percent) => price.discounted_by_percent(*percent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+
+    #[sqlx::test(fixtures(
+        "../fixtures/products.sql",
+        "../fixtures/product_categories.sql",
+        "../fixtures/pricing_rules.sql"
+    ))]
+    async fn discounted_prices(pool: PgPool) {
+        let products = sqlx::query!(
+            r#"
+            SELECT products.id as "id: ProductId", products.name, product_prices.price as "price: StregCents"
+            FROM products
+            JOIN product_prices
+            ON products.id = product_prices.product_id
+            WHERE products.id IN (1, 2, 11, 12, 13)
+            ORDER BY products.id
+            "#
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let prices = products.iter().map(|p| (p.id, p.price)).collect::<Vec<_>>();
+
+        let discounted_prices = get_discounted_prices(&prices, &pool).await.unwrap();
+
+        let discounted_prices = products
+            .iter()
+            .zip(discounted_prices)
+            .map(|(p, price)| (p.name.as_str(), price.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            discounted_prices,
+            [
+                // Fixed price rule
+                ("Enabled", "5.00".to_string()),
+                // No rules
+                ("No aliases", "12.00".to_string()),
+                // The rule is for another weekday
+                ("Øl", "10.00".to_string()),
+                // The fixed price is above the original price, so the discount wins
+                ("Café", "13.50".to_string()),
+                // Percentage discount on the category
+                ("Sodavand", "9.35".to_string())
+            ]
+        );
+    }
+
+    #[sqlx::test(fixtures("../fixtures/products.sql"))]
+    async fn pricing_rule_weekdays_are_local(pool: PgPool) {
+        // Kiritimati is UTC+14 and Pago Pago UTC-11, so it is never the same weekday in both
+        sqlx::query!(
+            r#"
+            INSERT INTO pricing_rules(product_id, weekday, starts_at, ends_at, fixed_price)
+            VALUES
+              (1, EXTRACT(ISODOW FROM now() AT TIME ZONE 'Pacific/Kiritimati'), '00:00', '24:00', 100),
+              (2, EXTRACT(ISODOW FROM now() AT TIME ZONE 'Pacific/Pago_Pago'),  '00:00', '24:00', 100)
+            "#
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let mut connection = pool.acquire().await.unwrap();
+        sqlx::query!("SET TIME ZONE 'Pacific/Pago_Pago'")
+            .execute(&mut *connection)
+            .await
+            .unwrap();
+        sqlx::query!("SELECT set_config('stregsystemet.time_zone', 'Pacific/Kiritimati', false)")
+            .fetch_one(&mut *connection)
+            .await
+            .unwrap();
+
+        let prices = [
+            ("1".parse().unwrap(), StregCents::from_cents(700)),
+            ("2".parse().unwrap(), StregCents::from_cents(1200)),
+        ];
+        let discounted_prices = get_discounted_prices(&prices, &mut *connection)
+            .await
+            .unwrap()
+            .iter()
+            .map(StregCents::to_string)
+            .collect::<Vec<_>>();
+
+        assert_eq!(discounted_prices, ["1.00", "12.00"]);
+    }
+}
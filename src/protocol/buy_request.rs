@@ -1,9 +1,11 @@
+use std::num::NonZeroU32;
+
 use axum::http::StatusCode;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
-    dso::product::ProductId,
+    dso::{order::OrderId, product::ProductId},
     quickbuy::{executor::MultiBuyExecutorError, parser::QuickBuyParseError},
     responses::result_json::HttpStatusCode,
 };
@@ -13,6 +15,11 @@ pub struct BuyRequest {
     pub quickbuy: String,
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct UndoRequest {
+    pub username: String,
+}
+
 #[derive(Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum BuyResponse {
@@ -21,16 +28,95 @@ pub enum BuyResponse {
     },
     MultiBuy {
         username: String,
+        order_id: OrderId,
+        bought_products: Vec<BoughtProduct>,
+        product_price_sum: String,
+        new_user_balance: String,
+        shares: Vec<PaymentShare>,
+        discounts: Vec<AppliedDiscount>,
+    },
+    Undo {
+        username: String,
+        order_id: OrderId,
+        refunded_price_sum: String,
+        new_user_balance: String,
+    },
+    Repeat {
+        username: String,
+        repeated_order_id: OrderId,
+        order_id: OrderId,
         bought_products: Vec<BoughtProduct>,
         product_price_sum: String,
         new_user_balance: String,
+        shares: Vec<PaymentShare>,
+        discounts: Vec<AppliedDiscount>,
+    },
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum PreviewResponse {
+    Username {
+        username: String,
+    },
+    MultiBuy {
+        username: String,
+        products: Vec<PreviewedProduct>,
+        product_price_sum: String,
+        new_user_balance: String,
+        shares: Vec<PaymentShare>,
+        discounts: Vec<AppliedDiscount>,
+    },
+    Undo {
+        username: String,
+    },
+    Repeat {
+        username: String,
+        repeated_order_id: OrderId,
+        products: Vec<PreviewedProduct>,
+        product_price_sum: String,
+        new_user_balance: String,
+        shares: Vec<PaymentShare>,
+        discounts: Vec<AppliedDiscount>,
     },
 }
 
+#[derive(Deserialize, Serialize)]
+pub struct PreviewedProduct {
+    pub product_id: ProductId,
+    pub product_name: String,
+    // The user the product was bought for, if not the buyer
+    pub consumer: Option<String>,
+    pub amount: NonZeroU32,
+    pub unit_price: String,
+    pub line_total: String,
+}
+
+// What each user paying for a purchase pays, in the order they were listed
+#[derive(Deserialize, Serialize)]
+pub struct PaymentShare {
+    pub username: String,
+    pub share: String,
+    pub new_user_balance: String,
+}
+
+// A bundle offer that made the purchase cheaper, and how much cheaper in total
+#[derive(Deserialize, Serialize)]
+pub struct AppliedDiscount {
+    pub name: String,
+    pub times: u32,
+    pub discount: String,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct BoughtProduct {
     pub product_id: ProductId,
-    pub amount: i32,
+    pub product_name: String,
+    // The user the product was bought for, if not the buyer
+    pub consumer: Option<String>,
+    pub amount: u32,
+    pub unit_price: String,
+    pub line_total: String,
 }
 
 #[derive(Error, Debug, Serialize)]
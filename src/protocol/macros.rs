@@ -0,0 +1,72 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+use serde_with::DisplayFromStr;
+use thiserror::Error;
+
+use crate::quickbuy::parser::QuickBuyParseError;
+use crate::responses::result_json::HttpStatusCode;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QuickBuyMacro {
+    pub name: String,
+    pub expansion: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MacrosResponse {
+    pub macros: Vec<QuickBuyMacro>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetMacroRequest {
+    pub username: String,
+    pub name: String,
+    pub expansion: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteMacroRequest {
+    pub username: String,
+    pub name: String,
+}
+
+#[serde_as]
+#[derive(Error, Debug, Serialize)]
+#[serde(tag = "type", content = "context")]
+pub enum MacroError {
+    #[error("database error: {0}")]
+    DbError(
+        #[serde_as(as = "DisplayFromStr")]
+        #[from]
+        sqlx::Error,
+    ),
+
+    #[error("invalid username: {username}")]
+    InvalidUsername {
+        username: String,
+        suggestions: Vec<String>,
+    },
+
+    #[error("invalid macro name: {0}")]
+    InvalidName(String),
+
+    #[error("macro name is a product alias: {0}")]
+    NameIsAlias(String),
+
+    #[error("invalid macro expansion: {0}")]
+    InvalidExpansion(QuickBuyParseError),
+
+    #[error("unknown macro: {0}")]
+    UnknownMacro(String),
+}
+
+impl HttpStatusCode for MacroError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MacroError::DbError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            MacroError::UnknownMacro(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
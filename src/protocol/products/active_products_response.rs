@@ -3,21 +3,50 @@ use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use thiserror::Error;
 
-use crate::{dso::product::ProductId, responses::result_json::HttpStatusCode};
+use crate::{
+    dso::{category::CategoryId, product::ProductId, quota::QuotaPeriod},
+    responses::result_json::HttpStatusCode,
+};
 
 #[derive(Deserialize, Serialize)]
 pub struct ActiveProductsResponse {
     pub products: Vec<ActiveProduct>,
 }
 
+// The active products grouped by category, categories come in menu order.
+// Uncategorized products are in a last group without a name.
+#[derive(Deserialize, Serialize)]
+pub struct ActiveProductCategoriesResponse {
+    pub categories: Vec<ActiveProductCategory>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ActiveProductCategory {
+    pub name: Option<String>,
+    pub products: Vec<ActiveProduct>,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ActiveProduct {
     pub id: ProductId,
     pub name: String,
+    // What the product costs right now, which is below original_price during e.g. a happy hour
     pub price: String,
+    pub original_price: String,
+    pub category_id: Option<CategoryId>,
+    pub category: Option<String>,
+    pub sold_out: bool,
+    pub age_restricted: bool,
+    pub quota: Option<ActiveProductQuota>,
     pub aliases: Vec<String>,
 }
 
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ActiveProductQuota {
+    pub max_amount: i32,
+    pub period: QuotaPeriod,
+}
+
 // TODO: Should not be here
 #[serde_as]
 #[derive(Error, Debug, Serialize)]
@@ -30,15 +30,18 @@ pub enum UserInfoError {
         sqlx::Error,
     ),
 
-    #[error("invalid username: {0}")]
-    InvalidUsername(String),
+    #[error("invalid username: {username}")]
+    InvalidUsername {
+        username: String,
+        suggestions: Vec<String>,
+    },
 }
 
 impl HttpStatusCode for UserInfoError {
     fn status_code(&self) -> axum::http::StatusCode {
         match self {
             UserInfoError::DbError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            UserInfoError::InvalidUsername(_) => StatusCode::BAD_REQUEST,
+            UserInfoError::InvalidUsername { .. } => StatusCode::BAD_REQUEST,
         }
     }
 }
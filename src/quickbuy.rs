@@ -1,2 +1,4 @@
+pub mod bundles;
 pub mod executor;
+pub mod macros;
 pub mod parser;
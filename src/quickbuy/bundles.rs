@@ -0,0 +1,368 @@
+use std::{collections::BTreeMap, num::NonZeroU32};
+
+use sqlx::PgExecutor;
+
+use crate::dso::{
+    category::CategoryId,
+    product::ProductId,
+    streg_cents::{stregcents_sum, StregCents},
+};
+
+// A line of a purchase as seen by the bundles, units on a line all cost the same before bundling
+pub struct BundleLine {
+    pub product_id: ProductId,
+    pub category_id: Option<CategoryId>,
+    pub unit_price: StregCents,
+    pub amount: NonZeroU32,
+}
+
+pub struct Bundle {
+    name: String,
+    price: StregCents,
+    items: Vec<BundleItem>,
+}
+
+struct BundleItem {
+    target: BundleTarget,
+    amount: u32,
+}
+
+enum BundleTarget {
+    Product(ProductId),
+    Category(CategoryId),
+}
+
+pub struct BundledLines {
+    // For every line, the prices its units are sold at and how many units are sold at each price
+    pub sale_prices: Vec<Vec<(StregCents, NonZeroU32)>>,
+    pub applied_bundles: Vec<AppliedBundle>,
+}
+
+pub struct AppliedBundle {
+    pub name: String,
+    pub times: u32,
+    // How much cheaper the purchase became, in total over all the times the bundle was applied
+    pub discount: StregCents,
+}
+
+pub async fn get_active_bundles<'a, E>(executor: E) -> Result<Vec<Bundle>, sqlx::Error>
+where
+    E: PgExecutor<'a>,
+{
+    let bundle_items = sqlx::query!(
+        r#"
+        SELECT bundles.id, bundles.name, bundles.price as "price: StregCents", bundle_items.product_id as "product_id: ProductId", bundle_items.category_id as "category_id: CategoryId", bundle_items.amount
+        FROM bundles
+        JOIN bundle_items
+        ON bundles.id = bundle_items.bundle_id
+        WHERE is_active_now(bundles.active, bundles.activate_after_timestamp, bundles.deactivate_after_timestamp)
+        ORDER BY bundles.id, bundle_items.id
+        "#
+    )
+    .fetch_all(executor)
+    .await?;
+
+    let mut bundles: Vec<(i32, Bundle)> = vec![];
+    for bundle_item in bundle_items {
+        let target = match (bundle_item.product_id, bundle_item.category_id) {
+            (Some(product_id), _) => BundleTarget::Product(product_id),
+            (None, Some(category_id)) => BundleTarget::Category(category_id),
+            (None, None) => unreachable!("a bundle item has exactly one target"),
+        };
+        let item = BundleItem {
+            target,
+            amount: u32::try_from(bundle_item.amount).expect("bundle item amounts are positive"),
+        };
+
+        match bundles.last_mut() {
+            Some((id, bundle)) if *id == bundle_item.id => bundle.items.push(item),
+            _ => bundles.push((
+                bundle_item.id,
+                Bundle {
+                    name: bundle_item.name,
+                    price: bundle_item.price,
+                    items: vec![item],
+                },
+            )),
+        }
+    }
+
+    Ok(bundles.into_iter().map(|(_, bundle)| bundle).collect())
+}
+
+// Applies the bundles in order, each as many times as it makes the purchase cheaper.
+// A bundle takes the most expensive matching units first, so the discount is as large as possible.
+// The bundle price is split between its units in proportion to their prices, so the prices
+// the units are sold at add up to exactly what is paid.
+pub fn apply_bundles(lines: &[BundleLine], bundles: &[Bundle]) -> Option<BundledLines> {
+    let mut remaining = lines.iter().map(|l| l.amount.get()).collect::<Vec<u32>>();
+    let mut bundled_prices = vec![BTreeMap::<StregCents, u32>::new(); lines.len()];
+    let mut applied_bundles = vec![];
+
+    for bundle in bundles {
+        let mut applied_bundle = AppliedBundle {
+            name: bundle.name.clone(),
+            times: 0,
+            discount: StregCents::default(),
+        };
+
+        while let Some(taken) = take_bundle_units(lines, &remaining, bundle) {
+            let taken_prices = taken
+                .iter()
+                .map(|&(i, amount)| lines[i].unit_price * NonZeroU32::new(amount)?)
+                .collect::<Option<Vec<StregCents>>>()?;
+            let regular_price = stregcents_sum(taken_prices.iter().copied().map(Some))?;
+            if bundle.price >= regular_price {
+                break;
+            }
+
+            // Until one of the lines runs out, every application takes the same units at the same prices,
+            // so all those applications are done at once instead of one at a time
+            let times = taken
+                .iter()
+                .map(|&(i, amount)| remaining[i] / amount)
+                .min()
+                .and_then(NonZeroU32::new)?;
+
+            let shares = bundle.price.split_proportionally(&taken_prices);
+            for (&(i, amount), share) in taken.iter().zip(shares) {
+                remaining[i] -= amount * times.get();
+                let unit_prices = share.split_evenly(NonZeroU32::new(amount)?);
+                for unit_price in unit_prices {
+                    *bundled_prices[i].entry(unit_price).or_default() += times.get();
+                }
+            }
+
+            applied_bundle.times = applied_bundle.times.checked_add(times.get())?;
+            applied_bundle.discount =
+                (applied_bundle.discount + ((regular_price - bundle.price)? * times)?)?;
+        }
+
+        if applied_bundle.times > 0 {
+            applied_bundles.push(applied_bundle);
+        }
+    }
+
+    let sale_prices = lines
+        .iter()
+        .zip(bundled_prices)
+        .zip(remaining)
+        .map(|((line, bundled_prices), remaining)| {
+            bundled_prices
+                .into_iter()
+                .chain([(line.unit_price, remaining)])
+                .filter_map(|(price, amount)| Some((price, NonZeroU32::new(amount)?)))
+                .collect()
+        })
+        .collect();
+
+    Some(BundledLines {
+        sale_prices,
+        applied_bundles,
+    })
+}
+
+// The units one application of the bundle would take from each line, or None if the lines
+// don't have enough units left for the bundle
+fn take_bundle_units(
+    lines: &[BundleLine],
+    remaining: &[u32],
+    bundle: &Bundle,
+) -> Option<Vec<(usize, u32)>> {
+    let mut remaining = remaining.to_vec();
+    let mut taken: Vec<(usize, u32)> = vec![];
+
+    for item in &bundle.items {
+        let mut candidates = (0..lines.len())
+            .filter(|&i| remaining[i] > 0 && item.target.matches(&lines[i]))
+            .collect::<Vec<usize>>();
+        candidates.sort_by_key(|&i| std::cmp::Reverse(lines[i].unit_price));
+
+        let mut needed = item.amount;
+        for i in candidates {
+            let amount = needed.min(remaining[i]);
+            remaining[i] -= amount;
+            needed -= amount;
+            match taken.iter_mut().find(|(j, _)| *j == i) {
+                Some((_, taken_amount)) => *taken_amount += amount,
+                None => taken.push((i, amount)),
+            }
+
+            if needed == 0 {
+                break;
+            }
+        }
+
+        if needed > 0 {
+            return None;
+        }
+    }
+
+    Some(taken)
+}
+
+impl BundleTarget {
+    fn matches(&self, line: &BundleLine) -> bool {
+        match self {
+            BundleTarget::Product(product_id) => *product_id == line.product_id,
+            BundleTarget::Category(category_id) => Some(*category_id) == line.category_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(
+        product_id: &str,
+        category_id: Option<CategoryId>,
+        unit_price: i64,
+        amount: u32,
+    ) -> BundleLine {
+        BundleLine {
+            product_id: product_id.parse().unwrap(),
+            category_id,
+            unit_price: StregCents::from_cents(unit_price),
+            amount: NonZeroU32::new(amount).unwrap(),
+        }
+    }
+
+    fn bundle(price: i64, items: Vec<(BundleTarget, u32)>) -> Bundle {
+        Bundle {
+            name: "bundle".to_string(),
+            price: StregCents::from_cents(price),
+            items: items
+                .into_iter()
+                .map(|(target, amount)| BundleItem { target, amount })
+                .collect(),
+        }
+    }
+
+    fn sale_prices(bundled_lines: &BundledLines) -> Vec<Vec<(String, u32)>> {
+        bundled_lines
+            .sale_prices
+            .iter()
+            .map(|prices| {
+                prices
+                    .iter()
+                    .map(|(price, amount)| (price.to_string(), amount.get()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn category_bundle_takes_units_from_several_lines() {
+        let lines = [
+            line("13", Some(CategoryId::new(1)), 1100, 2),
+            line("14", Some(CategoryId::new(1)), 1100, 2),
+        ];
+        let bundles = [bundle(
+            2000,
+            vec![(BundleTarget::Category(CategoryId::new(1)), 3)],
+        )];
+
+        let bundled_lines = apply_bundles(&lines, &bundles).unwrap();
+
+        assert_eq!(
+            sale_prices(&bundled_lines),
+            [
+                vec![("6.67".to_string(), 2)],
+                vec![("6.66".to_string(), 1), ("11.00".to_string(), 1)]
+            ]
+        );
+        assert_eq!(bundled_lines.applied_bundles.len(), 1);
+        assert_eq!(bundled_lines.applied_bundles[0].times, 1);
+        assert_eq!(
+            bundled_lines.applied_bundles[0].discount.to_string(),
+            "13.00"
+        );
+    }
+
+    #[test]
+    fn bundle_applied_several_times() {
+        let lines = [line("13", Some(CategoryId::new(1)), 1100, 7)];
+        let bundles = [bundle(
+            2000,
+            vec![(BundleTarget::Category(CategoryId::new(1)), 3)],
+        )];
+
+        let bundled_lines = apply_bundles(&lines, &bundles).unwrap();
+
+        assert_eq!(bundled_lines.applied_bundles[0].times, 2);
+        assert_eq!(
+            bundled_lines.applied_bundles[0].discount.to_string(),
+            "26.00"
+        );
+        assert_eq!(
+            sale_prices(&bundled_lines),
+            [vec![
+                ("6.66".to_string(), 2),
+                ("6.67".to_string(), 4),
+                ("11.00".to_string(), 1)
+            ]]
+        );
+    }
+
+    #[test]
+    fn bundle_applied_to_huge_amount() {
+        let lines = [line("13", Some(CategoryId::new(1)), 1100, 2_000_000_000)];
+        let bundles = [bundle(
+            2000,
+            vec![(BundleTarget::Category(CategoryId::new(1)), 3)],
+        )];
+
+        let bundled_lines = apply_bundles(&lines, &bundles).unwrap();
+
+        assert_eq!(bundled_lines.applied_bundles[0].times, 666_666_666);
+        assert_eq!(
+            bundled_lines.applied_bundles[0].discount.to_string(),
+            "8666666658.00"
+        );
+        assert_eq!(
+            sale_prices(&bundled_lines),
+            [vec![
+                ("6.66".to_string(), 666_666_666),
+                ("6.67".to_string(), 1_333_333_332),
+                ("11.00".to_string(), 2)
+            ]]
+        );
+    }
+
+    #[test]
+    fn bundle_split_in_proportion_to_prices() {
+        let lines = [line("11", None, 1000, 1), line("12", None, 1500, 1)];
+        let bundles = [bundle(
+            2000,
+            vec![
+                (BundleTarget::Product("11".parse().unwrap()), 1),
+                (BundleTarget::Product("12".parse().unwrap()), 1),
+            ],
+        )];
+
+        let bundled_lines = apply_bundles(&lines, &bundles).unwrap();
+
+        assert_eq!(
+            sale_prices(&bundled_lines),
+            [
+                vec![("8.00".to_string(), 1)],
+                vec![("12.00".to_string(), 1)]
+            ]
+        );
+    }
+
+    #[test]
+    fn bundle_not_applied_when_more_expensive() {
+        let lines = [line("13", Some(CategoryId::new(1)), 500, 3)];
+        let bundles = [bundle(
+            2000,
+            vec![(BundleTarget::Category(CategoryId::new(1)), 3)],
+        )];
+
+        let bundled_lines = apply_bundles(&lines, &bundles).unwrap();
+
+        assert!(bundled_lines.applied_bundles.is_empty());
+        assert_eq!(sale_prices(&bundled_lines), [vec![("5.00".to_string(), 3)]]);
+    }
+}
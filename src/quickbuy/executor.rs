@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, num::NonZeroU32, time::Duration};
 
 use serde::Serialize;
 use serde_with::serde_as;
@@ -9,57 +9,534 @@ use thiserror::Error;
 use tracing::trace;
 
 use crate::dso::{
+    category::CategoryId,
+    order::OrderId,
     product::ProductId,
+    quota::QuotaPeriod,
     streg_cents::{stregcents_sum, StregCents},
     user::UserId,
 };
-use crate::protocol::buy_request::BoughtProduct;
+use crate::pricing::get_discounted_prices;
+use crate::protocol::buy_request::{
+    AppliedDiscount, BoughtProduct, PaymentShare, PreviewedProduct,
+};
+
+use super::bundles::{apply_bundles, get_active_bundles, AppliedBundle, BundleLine};
+use super::macros::expand_macros;
+use super::parser::{MultiBuyProduct, Span};
+
+// Minimum age for buying age restricted products
+const LEGAL_AGE_YEARS: i32 = 18;
+
+// How many products are suggested when an unknown product is entered
+const MAX_PRODUCT_SUGGESTIONS: i64 = 3;
 
-use super::parser::MultiBuyProduct;
+// Aliases are at most 128 characters, so longer input can't be a typo of one
+const MAX_SUGGESTABLE_PRODUCT_NAME_LENGTH: usize = 128;
+
+const MAX_USERNAME_SUGGESTIONS: i64 = 5;
+
+// Same reasoning as for product names, usernames are at most 128 characters
+const MAX_SUGGESTABLE_USERNAME_LENGTH: usize = 128;
 
 pub async fn execute_multi_buy_query(
     username: &str,
+    split_with: &[String],
+    multi_buy_products: &[MultiBuyProduct],
+    suggest_usernames: bool,
+    pool: &PgPool,
+) -> Result<MultiBuyResult, MultiBuyExecutorError> {
+    let mut transaction = pool.begin().await?;
+
+    let multi_buy_products = expand_macros(username, multi_buy_products, &mut transaction).await?;
+    let multi_buy_result = purchase_multi_buy(
+        username,
+        split_with,
+        &multi_buy_products,
+        suggest_usernames,
+        &mut transaction,
+    )
+    .await?;
+
+    transaction.commit().await?;
+
+    trace!(target: "stregsystemet", "user {} just bought products totalling {} kr", username, multi_buy_result.product_price_sum);
+
+    Ok(multi_buy_result)
+}
+
+// Buys the products of the user's most recent order that was not undone again,
+// at the current prices and only if they can still be bought
+pub async fn execute_repeat_query(
+    username: &str,
+    suggest_usernames: bool,
+    pool: &PgPool,
+) -> Result<(OrderId, MultiBuyResult), MultiBuyExecutorError> {
+    let mut transaction = pool.begin().await?;
+
+    let repeatable_order = get_repeatable_order(
+        username,
+        RowLocks::Take,
+        suggest_usernames,
+        &mut transaction,
+    )
+    .await?;
+    let multi_buy_result = purchase_multi_buy(
+        username,
+        &repeatable_order.split_with,
+        &repeatable_order.products,
+        suggest_usernames,
+        &mut transaction,
+    )
+    .await?;
+
+    transaction.commit().await?;
+
+    trace!(target: "stregsystemet", "user {} just repeated order {:?} totalling {} kr", username, repeatable_order.order_id, multi_buy_result.product_price_sum);
+
+    Ok((repeatable_order.order_id, multi_buy_result))
+}
+
+async fn purchase_multi_buy(
+    username: &str,
+    split_with: &[String],
+    multi_buy_products: &[MultiBuyProduct],
+    suggest_usernames: bool,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<MultiBuyResult, MultiBuyExecutorError> {
+    let prepared_multi_buy = prepare_multi_buy(
+        username,
+        split_with,
+        multi_buy_products,
+        RowLocks::Take,
+        suggest_usernames,
+        transaction,
+    )
+    .await?;
+
+    let order_id = create_order(prepared_multi_buy.user_id, transaction).await?;
+    purchase_products(
+        prepared_multi_buy.user_id,
+        order_id,
+        &prepared_multi_buy.purchase_lines,
+        transaction,
+    )
+    .await?;
+    // The user making the order pays for the sales, the others pay their share to them
+    insert_order_shares(order_id, &prepared_multi_buy.payers[1..], transaction).await?;
+
+    let bought_products = prepared_multi_buy
+        .purchase_lines
+        .into_iter()
+        .map(|l| {
+            let line_total =
+                (l.unit_price * l.amount).ok_or(MultiBuyExecutorError::StregCentsOverflow)?;
+            Ok(BoughtProduct {
+                product_id: l.product_id,
+                product_name: l.product_name,
+                consumer: l.consumer,
+                amount: l.amount.get(),
+                unit_price: l.unit_price.to_string(),
+                line_total: line_total.to_string(),
+            })
+        })
+        .collect::<Result<Vec<BoughtProduct>, MultiBuyExecutorError>>()?;
+    Ok(MultiBuyResult {
+        order_id,
+        bought_products,
+        product_price_sum: prepared_multi_buy.product_price_sum,
+        new_user_balance: prepared_multi_buy.payers[0].new_user_balance,
+        shares: get_payment_shares(prepared_multi_buy.payers),
+        discounts: get_applied_discounts(prepared_multi_buy.applied_bundles),
+    })
+}
+
+// Runs every check a purchase would, but rolls back instead of buying anything
+pub async fn preview_multi_buy_query(
+    username: &str,
+    split_with: &[String],
     multi_buy_products: &[MultiBuyProduct],
+    suggest_usernames: bool,
+    pool: &PgPool,
+) -> Result<MultiBuyPreview, MultiBuyExecutorError> {
+    let mut transaction = pool.begin().await?;
+
+    let multi_buy_products = expand_macros(username, multi_buy_products, &mut transaction).await?;
+    let preview = preview_multi_buy(
+        username,
+        split_with,
+        &multi_buy_products,
+        suggest_usernames,
+        &mut transaction,
+    )
+    .await?;
+
+    transaction.rollback().await?;
+
+    Ok(preview)
+}
+
+// Shows what repeating the user's most recent order would buy, without buying anything
+pub async fn preview_repeat_query(
+    username: &str,
+    suggest_usernames: bool,
     pool: &PgPool,
-) -> Result<(Vec<BoughtProduct>, StregCents, StregCents), MultiBuyExecutorError> {
+) -> Result<(OrderId, MultiBuyPreview), MultiBuyExecutorError> {
     let mut transaction = pool.begin().await?;
 
-    let user_id = get_user_id_by_name(username, &mut *transaction)
-        .await?
-        .ok_or_else(|| MultiBuyExecutorError::InvalidUsername(username.to_string()))?;
+    let repeatable_order = get_repeatable_order(
+        username,
+        RowLocks::Skip,
+        suggest_usernames,
+        &mut transaction,
+    )
+    .await?;
+    let preview = preview_multi_buy(
+        username,
+        &repeatable_order.split_with,
+        &repeatable_order.products,
+        suggest_usernames,
+        &mut transaction,
+    )
+    .await?;
+
+    transaction.rollback().await?;
+
+    Ok((repeatable_order.order_id, preview))
+}
+
+async fn preview_multi_buy(
+    username: &str,
+    split_with: &[String],
+    multi_buy_products: &[MultiBuyProduct],
+    suggest_usernames: bool,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<MultiBuyPreview, MultiBuyExecutorError> {
+    let prepared_multi_buy = prepare_multi_buy(
+        username,
+        split_with,
+        multi_buy_products,
+        RowLocks::Skip,
+        suggest_usernames,
+        transaction,
+    )
+    .await?;
+
+    let previewed_products = prepared_multi_buy
+        .priced_products
+        .into_iter()
+        .map(|p| {
+            let line_total = (p.unit_price * p.multi_buy_product.amount)
+                .ok_or(MultiBuyExecutorError::StregCentsOverflow)?;
+            Ok(PreviewedProduct {
+                product_id: p.product_id,
+                product_name: p.product_name,
+                consumer: p.multi_buy_product.consumer.clone(),
+                amount: p.multi_buy_product.amount,
+                unit_price: p.unit_price.to_string(),
+                line_total: line_total.to_string(),
+            })
+        })
+        .collect::<Result<Vec<PreviewedProduct>, MultiBuyExecutorError>>()?;
+
+    Ok(MultiBuyPreview {
+        products: previewed_products,
+        product_price_sum: prepared_multi_buy.product_price_sum,
+        new_user_balance: prepared_multi_buy.payers[0].new_user_balance,
+        shares: get_payment_shares(prepared_multi_buy.payers),
+        discounts: get_applied_discounts(prepared_multi_buy.applied_bundles),
+    })
+}
+
+fn get_applied_discounts(applied_bundles: Vec<AppliedBundle>) -> Vec<AppliedDiscount> {
+    applied_bundles
+        .into_iter()
+        .map(|b| AppliedDiscount {
+            name: b.name,
+            times: b.times,
+            discount: b.discount.to_string(),
+        })
+        .collect()
+}
+
+fn get_payment_shares(payers: Vec<Payer>) -> Vec<PaymentShare> {
+    payers
+        .into_iter()
+        .map(|p| PaymentShare {
+            username: p.username,
+            share: p.share.to_string(),
+            new_user_balance: p.new_user_balance.to_string(),
+        })
+        .collect()
+}
+
+// Resolves and prices the products and checks that the user may buy them.
+// Shared by purchases and previews so the two can never disagree.
+async fn prepare_multi_buy<'a>(
+    username: &str,
+    split_with: &[String],
+    multi_buy_products: &'a [MultiBuyProduct],
+    row_locks: RowLocks,
+    suggest_usernames: bool,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<PreparedMultiBuy<'a>, MultiBuyExecutorError> {
+    let mut payer_ids = vec![];
+    for payer in std::iter::once(username).chain(split_with.iter().map(String::as_str)) {
+        let Some(payer_id) = get_user_id_by_name(payer, &mut **transaction).await? else {
+            return Err(invalid_username(payer, suggest_usernames, &mut **transaction).await);
+        };
+        payer_ids.push((payer, payer_id));
+    }
+    let user_id = payer_ids[0].1;
+    let consumers = get_consumers(multi_buy_products, suggest_usernames, transaction).await?;
 
-    let user_balance = get_user_balance_by_id(user_id, &mut transaction).await?;
+    // Concurrent purchases for the same user must not both pass the balance check.
+    // Consumers are locked as well, as their quotas are checked below.
+    if row_locks == RowLocks::Take {
+        let user_ids = payer_ids
+            .iter()
+            .map(|&(_, payer_id)| payer_id)
+            .chain(consumers.iter().map(|c| c.user_id))
+            .collect::<Vec<UserId>>();
+        lock_users_by_ids(&user_ids, transaction).await?;
+    }
 
     let multi_buy_products_with_ids =
-        get_multi_buy_products_with_ids(multi_buy_products, &mut transaction).await?;
+        get_multi_buy_products_with_ids(multi_buy_products, transaction).await?;
 
-    let product_price_sum =
-        get_product_price_sum(&multi_buy_products_with_ids, &mut transaction).await?;
+    let priced_products =
+        price_multi_buy_products(multi_buy_products_with_ids, transaction).await?;
 
-    if user_balance < product_price_sum {
-        return Err(MultiBuyExecutorError::InsufficientFunds {
-            username: username.to_string(),
-            product_price_sum,
+    let mut purchase_lines = get_purchase_lines(&priced_products, user_id, &consumers)?;
+    let applied_bundles = apply_active_bundles(&mut purchase_lines, transaction).await?;
+    let product_price_sum = stregcents_sum(
+        purchase_lines
+            .iter()
+            .flat_map(|l| l.sale_prices.iter().map(|&(price, amount)| price * amount)),
+    )
+    .ok_or(MultiBuyExecutorError::StregCentsOverflow)?;
+
+    // Both the users paying and the users consuming must be old enough
+    let all_product_ids = purchase_lines
+        .iter()
+        .map(|l| l.product_id)
+        .collect::<Vec<ProductId>>();
+    for &(payer, payer_id) in &payer_ids {
+        if let Some((product_id, product_name)) =
+            get_age_restricted_product(payer_id, &all_product_ids, transaction).await?
+        {
+            return Err(MultiBuyExecutorError::AgeRestricted {
+                username: payer.to_string(),
+                product_name,
+                span: get_line_span(&purchase_lines, product_id, None),
+            });
+        }
+    }
+    for consumer in &consumers {
+        let consumed_product_ids = purchase_lines
+            .iter()
+            .filter(|l| l.consumer_id == consumer.user_id)
+            .map(|l| l.product_id)
+            .collect::<Vec<ProductId>>();
+        if let Some((product_id, product_name)) =
+            get_age_restricted_product(consumer.user_id, &consumed_product_ids, transaction).await?
+        {
+            return Err(MultiBuyExecutorError::AgeRestricted {
+                username: consumer.username.clone(),
+                product_name,
+                span: get_line_span(&purchase_lines, product_id, Some(consumer.user_id)),
+            });
+        }
+    }
+
+    let shares = product_price_sum.split_evenly(
+        NonZeroU32::new(payer_ids.len() as u32).expect("there is always at least one payer"),
+    );
+    let mut payers = vec![];
+    for (&(payer, payer_id), share) in payer_ids.iter().zip(shares) {
+        let user_balance = get_user_balance_by_id(payer_id, transaction).await?;
+        let user_credit_limit = get_user_credit_limit_by_id(payer_id, transaction).await?;
+        let remaining_credit =
+            (user_balance + user_credit_limit).ok_or(MultiBuyExecutorError::StregCentsOverflow)?;
+
+        if remaining_credit < share {
+            return Err(MultiBuyExecutorError::InsufficientFunds {
+                username: payer.to_string(),
+                product_price_sum: share,
+                remaining_credit,
+            });
+        }
+
+        payers.push(Payer {
+            username: payer.to_string(),
+            user_id: payer_id,
+            share,
+            new_user_balance: (user_balance - share)
+                .ok_or(MultiBuyExecutorError::StregCentsOverflow)?,
         });
     }
 
-    let new_user_balance =
-        (user_balance - product_price_sum).ok_or(MultiBuyExecutorError::StregCentsOverflow)?;
-    let bought_products =
-        purchase_products(user_id, &multi_buy_products_with_ids, &mut transaction).await?;
+    check_quotas(username, &purchase_lines, transaction).await?;
+    if row_locks == RowLocks::Take {
+        lock_stock_limited_products(&all_product_ids, transaction).await?;
+    }
+    check_stock(&purchase_lines, transaction).await?;
 
-    transaction.commit().await?;
+    Ok(PreparedMultiBuy {
+        user_id,
+        payers,
+        priced_products,
+        purchase_lines,
+        product_price_sum,
+        applied_bundles,
+    })
+}
+
+pub async fn execute_undo_query(
+    username: &str,
+    undo_window: Duration,
+    suggest_usernames: bool,
+    pool: &PgPool,
+) -> Result<(OrderId, StregCents, StregCents), MultiBuyExecutorError> {
+    // Undoing the order refunds the users sharing it, so they are locked together with the user in id order.
+    // They are only known once the order is, so if the last order changed before the locks were taken, start over.
+    loop {
+        let mut transaction = pool.begin().await?;
+
+        let Some(user_id) = get_user_id_by_name(username, &mut *transaction).await? else {
+            return Err(invalid_username(username, suggest_usernames, &mut *transaction).await);
+        };
+
+        let unlocked_last_order_id = get_last_order(user_id, undo_window, &mut transaction)
+            .await?
+            .map(|o| o.id);
+        let mut payer_ids = match unlocked_last_order_id {
+            Some(order_id) => get_order_sharer_ids(order_id, &mut transaction).await?,
+            None => vec![],
+        };
+        payer_ids.push(user_id);
+
+        // Serializes with purchases so the order being undone is really the most recent one
+        lock_users_by_ids(&payer_ids, &mut transaction).await?;
+
+        let last_order = get_last_order(user_id, undo_window, &mut transaction).await?;
+        if last_order.as_ref().map(|o| o.id) != unlocked_last_order_id {
+            transaction.rollback().await?;
+            continue;
+        }
+
+        let last_order = last_order
+            .filter(|o| o.within_undo_window && !o.reversed)
+            .ok_or_else(|| MultiBuyExecutorError::NoPurchaseToUndo(username.to_string()))?;
+
+        reverse_order(user_id, &last_order, &mut transaction).await?;
+
+        let new_user_balance = get_user_balance_by_id(user_id, &mut transaction).await?;
+
+        transaction.commit().await?;
+
+        trace!(target: "stregsystemet", "user {} just undid order {:?} totalling {} kr", username, last_order.id, last_order.price_sum);
+
+        return Ok((last_order.id, last_order.price_sum, new_user_balance));
+    }
+}
+
+// The user's most recent order that was not undone, in the form it would be typed in a quickbuy
+async fn get_repeatable_order(
+    username: &str,
+    row_locks: RowLocks,
+    suggest_usernames: bool,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<RepeatableOrder, MultiBuyExecutorError> {
+    let Some(user_id) = get_user_id_by_name(username, &mut **transaction).await? else {
+        return Err(invalid_username(username, suggest_usernames, &mut **transaction).await);
+    };
+
+    // Serializes with purchases so the order being repeated is really the most recent one
+    if row_locks == RowLocks::Take {
+        lock_users_by_ids(&[user_id], transaction).await?;
+    }
+
+    let order_id = sqlx::query_scalar!(
+        r#"
+        SELECT id as "id: OrderId"
+        FROM orders
+        WHERE user_id = $1 AND NOT EXISTS(SELECT 1 FROM order_reversals WHERE order_id = orders.id)
+        ORDER BY timestamp DESC, id DESC
+        LIMIT 1
+        "#,
+        user_id as UserId
+    )
+    .fetch_optional(&mut **transaction)
+    .await?
+    .ok_or_else(|| MultiBuyExecutorError::NoPurchaseToRepeat(username.to_string()))?;
+
+    // Products are referred to by id, so renamed products and changed aliases are still found
+    let order_lines = sqlx::query!(
+        r#"
+        SELECT
+          sales.product_id::text as "product_id!",
+          products.name,
+          is_active_now(products.active, products.activate_after_timestamp, products.deactivate_after_timestamp)
+            AND EXISTS(SELECT 1 FROM product_prices WHERE product_id = products.id AND valid_during @> now()) as "available!",
+          CASE WHEN sales.consumer_id = $2 THEN NULL ELSE consumers.username END as consumer,
+          COUNT(*) as "amount!"
+        FROM sales
+        JOIN products ON products.id = sales.product_id
+        JOIN users AS consumers ON consumers.id = sales.consumer_id
+        WHERE sales.order_id = $1
+        GROUP BY sales.product_id, products.id, sales.consumer_id, consumers.username
+        ORDER BY MIN(sales.id)
+        "#,
+        order_id as OrderId,
+        user_id as UserId
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+
+    // The order isn't typed by the user, so an unavailable product is reported by name rather than as an unknown id
+    if let Some(unavailable_line) = order_lines.iter().find(|l| !l.available) {
+        return Err(MultiBuyExecutorError::ProductUnavailable {
+            product_name: unavailable_line.name.clone(),
+        });
+    }
 
-    trace!(target: "stregsystemet", "user {} just bought products totalling {} kr", username, product_price_sum);
+    let split_with = sqlx::query_scalar!(
+        r#"
+        SELECT users.username
+        FROM order_shares
+        JOIN users ON users.id = order_shares.user_id
+        WHERE order_shares.order_id = $1
+        ORDER BY order_shares.id
+        "#,
+        order_id as OrderId
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
 
-    let bought_products = bought_products
+    let products = order_lines
         .into_iter()
-        .map(|(product_id, amount)| BoughtProduct { product_id, amount })
-        .collect();
-    Ok((bought_products, product_price_sum, new_user_balance))
+        .map(|l| {
+            Ok(MultiBuyProduct {
+                product_name: l.product_id,
+                amount: u32::try_from(l.amount)
+                    .ok()
+                    .and_then(NonZeroU32::new)
+                    .ok_or(MultiBuyExecutorError::AmountOverflow)?,
+                consumer: l.consumer,
+                span: Span::default(),
+            })
+        })
+        .collect::<Result<Vec<MultiBuyProduct>, MultiBuyExecutorError>>()?;
+
+    Ok(RepeatableOrder {
+        order_id,
+        split_with,
+        products,
+    })
 }
 
-async fn get_user_id_by_name<'a, E>(
+pub(crate) async fn get_user_id_by_name<'a, E>(
     username: &str,
     executor: E,
 ) -> Result<Option<UserId>, sqlx::Error>
@@ -78,10 +555,114 @@ where
     .await
 }
 
-pub async fn username_exists(username: &str, pool: &PgPool) -> Result<(), MultiBuyExecutorError> {
-    get_user_id_by_name(username, pool)
-        .await?
-        .ok_or_else(|| MultiBuyExecutorError::InvalidUsername(username.to_string()))?;
+pub async fn username_exists(
+    username: &str,
+    suggest_usernames: bool,
+    pool: &PgPool,
+) -> Result<(), MultiBuyExecutorError> {
+    match get_user_id_by_name(username, pool).await? {
+        Some(_) => Ok(()),
+        None => Err(invalid_username(username, suggest_usernames, pool).await),
+    }
+}
+
+async fn invalid_username<'a, E>(
+    username: &str,
+    suggest_usernames: bool,
+    executor: E,
+) -> MultiBuyExecutorError
+where
+    E: PgExecutor<'a>,
+{
+    // Suggesting usernames reveals who has an account, so it is opt-in
+    if !suggest_usernames {
+        return MultiBuyExecutorError::InvalidUsername {
+            username: username.to_string(),
+            suggestions: vec![],
+        };
+    }
+
+    match get_username_suggestions(username, executor).await {
+        Ok(suggestions) => MultiBuyExecutorError::InvalidUsername {
+            username: username.to_string(),
+            suggestions,
+        },
+        Err(e) => e.into(),
+    }
+}
+
+// Usernames starting with username come first, followed by usernames within a small edit distance
+pub async fn get_username_suggestions<'a, E>(
+    username: &str,
+    executor: E,
+) -> Result<Vec<String>, sqlx::Error>
+where
+    E: PgExecutor<'a>,
+{
+    if username.chars().count() > MAX_SUGGESTABLE_USERNAME_LENGTH {
+        return Ok(vec![]);
+    }
+
+    sqlx::query_scalar!(
+        r#"
+        SELECT username
+        FROM users
+        WHERE starts_with(LOWER(username), LOWER($1)) OR levenshtein(LOWER($1), LOWER(username)) <= GREATEST(2, LENGTH($1) / 3)
+        ORDER BY starts_with(LOWER(username), LOWER($1)) DESC, levenshtein(LOWER($1), LOWER(username)), username
+        LIMIT $2
+        "#,
+        username,
+        MAX_USERNAME_SUGGESTIONS
+    )
+    .fetch_all(executor)
+    .await
+}
+
+// Resolves the users products are bought for, in order of first appearance
+async fn get_consumers(
+    multi_buy_products: &[MultiBuyProduct],
+    suggest_usernames: bool,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Vec<Consumer>, MultiBuyExecutorError> {
+    let mut consumers: Vec<Consumer> = vec![];
+    for consumer in multi_buy_products
+        .iter()
+        .filter_map(|p| p.consumer.as_ref())
+    {
+        if consumers.iter().any(|c| &c.username == consumer) {
+            continue;
+        }
+
+        let Some(user_id) = get_user_id_by_name(consumer, &mut **transaction).await? else {
+            return Err(invalid_username(consumer, suggest_usernames, &mut **transaction).await);
+        };
+        consumers.push(Consumer {
+            username: consumer.clone(),
+            user_id,
+        });
+    }
+
+    Ok(consumers)
+}
+
+async fn lock_users_by_ids(
+    user_ids: &[UserId],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<(), sqlx::Error> {
+    // The locks are held until the transaction commits or rolls back.
+    // Always locking in id order keeps purchases involving the same users from deadlocking.
+    sqlx::query!(
+        r#"
+        SELECT id
+        FROM users
+        WHERE id = ANY($1)
+        ORDER BY id
+        FOR UPDATE
+        "#,
+        user_ids as &[UserId]
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
 
     Ok(())
 }
@@ -92,204 +673,2535 @@ async fn get_user_balance_by_id(
 ) -> Result<StregCents, sqlx::Error> {
     sqlx::query_scalar!(
         r#"
-        SELECT ((SELECT COALESCE(SUM(amount), 0) FROM deposits WHERE user_id = $1) - (SELECT COALESCE(SUM(price), 0) FROM sales WHERE user_id = $1))::bigint as "money!: StregCents"
+        SELECT balance as "balance: StregCents"
+        FROM users
+        WHERE id = $1
         "#,
-        user_id as UserId)
-        .fetch_one(&mut **transaction)
-        .await
+        user_id as UserId
+    )
+    .fetch_one(&mut **transaction)
+    .await
+}
+
+async fn get_user_credit_limit_by_id(
+    user_id: UserId,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<StregCents, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT credit_limit as "credit_limit: StregCents"
+        FROM users
+        WHERE id = $1
+        "#,
+        user_id as UserId
+    )
+    .fetch_one(&mut **transaction)
+    .await
+}
+
+// Returns the id and name of the first product the user is not allowed to buy due to their age.
+// Users without a birth date are treated as underage. Birthdays start at midnight local time.
+async fn get_age_restricted_product(
+    user_id: UserId,
+    product_ids: &[ProductId],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Option<(ProductId, String)>, sqlx::Error> {
+    let product = sqlx::query!(
+        r#"
+        SELECT id as "id: ProductId", name
+        FROM products
+        WHERE id = ANY($1) AND age_restricted
+          AND NOT COALESCE((SELECT birth_date <= (now() AT TIME ZONE local_time_zone())::date - make_interval(years => $3) FROM users WHERE id = $2), false)
+        ORDER BY id
+        LIMIT 1
+        "#,
+        product_ids as &[ProductId],
+        user_id as UserId,
+        LEGAL_AGE_YEARS
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+
+    Ok(product.map(|p| (p.id, p.name)))
+}
+
+async fn price_multi_buy_products<'a>(
+    multi_buy_products_with_ids: Vec<MultiBuyProductProductIdPair<'a>>,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Vec<PricedMultiBuyProduct<'a>>, MultiBuyExecutorError> {
+    let product_ids = multi_buy_products_with_ids
+        .iter()
+        .map(|p| p.product_id)
+        .collect::<Vec<ProductId>>();
+    let product_prices = get_product_prices_by_ids(&product_ids, transaction).await?;
+
+    let mut priced_products = vec![];
+    for p in multi_buy_products_with_ids {
+        let Some(product_price) = product_prices.get(&p.product_id) else {
+            return Err(invalid_product(p.multi_buy_product, transaction).await);
+        };
+
+        priced_products.push(PricedMultiBuyProduct {
+            multi_buy_product: p.multi_buy_product,
+            product_id: p.product_id,
+            product_name: product_price.name.clone(),
+            category_id: product_price.category_id,
+            unit_price: product_price.price,
+        });
+    }
+
+    Ok(priced_products)
+}
+
+async fn get_product_prices_by_ids(
+    product_ids: &[ProductId],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<HashMap<ProductId, ProductPrice>, sqlx::Error> {
+    // now() is the start of the transaction, which is also the timestamp the sales are recorded with
+    let product_prices = sqlx::query!(
+        r#"
+        SELECT products.id as "id: ProductId", products.name, products.category_id as "category_id: CategoryId", product_prices.price as "price: StregCents"
+        FROM products
+        JOIN product_prices
+        ON products.id = product_prices.product_id AND product_prices.valid_during @> now()
+        WHERE products.id = ANY($1) AND is_active_now(products.active, products.activate_after_timestamp, products.deactivate_after_timestamp)
+        "#,
+        product_ids as &[ProductId]
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+
+    let discounted_prices = get_discounted_prices(
+        &product_prices
+            .iter()
+            .map(|p| (p.id, p.price))
+            .collect::<Vec<_>>(),
+        &mut **transaction,
+    )
+    .await?;
+
+    Ok(product_prices
+        .into_iter()
+        .zip(discounted_prices)
+        .map(|(p, price)| {
+            (
+                p.id,
+                ProductPrice {
+                    name: p.name,
+                    category_id: p.category_id,
+                    price,
+                },
+            )
+        })
+        .collect())
+}
+
+async fn get_multi_buy_products_with_ids<'a>(
+    multi_buy_products: &'a [MultiBuyProduct],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Vec<MultiBuyProductProductIdPair<'a>>, MultiBuyExecutorError> {
+    // Everything that does not parse as a product id is looked up as an alias
+    let aliases = multi_buy_products
+        .iter()
+        .filter(|p| p.product_name.parse::<ProductId>().is_err())
+        .map(|p| p.product_name.clone())
+        .collect::<Vec<String>>();
+    let product_ids_by_alias = get_product_ids_by_aliases(&aliases, transaction).await?;
+
+    let mut products_with_ids = vec![];
+    for multi_buy_product in multi_buy_products {
+        let product_id = match multi_buy_product.product_name.parse::<ProductId>() {
+            Ok(product_id) => product_id,
+            Err(_) => match product_ids_by_alias.get(&multi_buy_product.product_name) {
+                Some(AliasMatch::Product(product_id)) => *product_id,
+                Some(AliasMatch::Ambiguous(candidates)) => {
+                    return Err(MultiBuyExecutorError::AmbiguousProduct {
+                        product_name: multi_buy_product.product_name.clone(),
+                        span: multi_buy_product.span,
+                        candidates: candidates.clone(),
+                    });
+                }
+                None => return Err(invalid_product(multi_buy_product, transaction).await),
+            },
+        };
+
+        products_with_ids.push(MultiBuyProductProductIdPair {
+            multi_buy_product,
+            product_id,
+        });
+    }
+
+    Ok(products_with_ids)
+}
+
+async fn invalid_product(
+    multi_buy_product: &MultiBuyProduct,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> MultiBuyExecutorError {
+    match get_product_suggestions(&multi_buy_product.product_name, transaction).await {
+        Ok(suggestions) => MultiBuyExecutorError::InvalidProduct {
+            product_name: multi_buy_product.product_name.clone(),
+            span: multi_buy_product.span,
+            suggestions,
+        },
+        Err(e) => e.into(),
+    }
 }
 
-async fn get_product_price_sum(
-    mutli_buy_products_with_ids: &[MultiBuyProductProductIdPair<'_>],
-    transaction: &mut Transaction<'static, Postgres>,
-) -> Result<StregCents, MultiBuyExecutorError> {
-    // TODO: How do you do this without having to do a ugly loop?
-    let mut products_with_prices = vec![];
+// Active products whose aliases or names are within a small edit distance of product_name, closest first
+async fn get_product_suggestions(
+    product_name: &str,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Vec<ProductSuggestion>, sqlx::Error> {
+    if product_name.chars().count() > MAX_SUGGESTABLE_PRODUCT_NAME_LENGTH {
+        return Ok(vec![]);
+    }
+
+    sqlx::query_as!(
+        ProductSuggestion,
+        r#"
+        SELECT product_id as "product_id!: ProductId", product_name as "product_name!", quickbuy as "quickbuy!"
+        FROM (
+            -- Prefer suggesting an alias over a product id when both are equally close
+            SELECT DISTINCT ON (candidates.product_id) candidates.product_id, candidates.product_name, candidates.quickbuy, candidates.distance
+            FROM (
+                SELECT products.id as product_id, products.name as product_name, product_aliases.alias_name as quickbuy, levenshtein(LOWER($1), product_aliases.alias_name) as distance, 0 as preference
+                FROM product_aliases
+                JOIN products
+                ON products.id = product_aliases.product_id
+                WHERE is_active_now(products.active, products.activate_after_timestamp, products.deactivate_after_timestamp)
+                UNION ALL
+                SELECT products.id, products.name, products.id::text, levenshtein(LOWER($1), LOWER(products.name)), 1
+                FROM products
+                WHERE is_active_now(products.active, products.activate_after_timestamp, products.deactivate_after_timestamp)
+            ) candidates
+            WHERE candidates.distance <= GREATEST(1, LENGTH($1) / 3)
+            ORDER BY candidates.product_id, candidates.distance, candidates.preference, candidates.quickbuy
+        ) suggestions
+        ORDER BY distance, product_name
+        LIMIT $2
+        "#,
+        product_name,
+        MAX_PRODUCT_SUGGESTIONS
+    )
+    .fetch_all(&mut **transaction)
+    .await
+}
+
+// An exact alias match always wins, otherwise every alias of an active product with the same normalized form
+// is a candidate. oe in the product name may also stand for ø.
+async fn get_product_ids_by_aliases(
+    product_names: &[String],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<HashMap<String, AliasMatch>, sqlx::Error> {
+    let alias_candidates = sqlx::query!(
+        r#"
+        SELECT product_names.product_name as "product_name!", product_aliases.product_id as "product_id: ProductId", products.name as candidate_name, product_aliases.alias_name, product_aliases.alias_name = LOWER(product_names.product_name) as "exact!"
+        FROM UNNEST($1::text[]) AS product_names(product_name)
+        JOIN product_aliases
+        ON product_aliases.alias_normalized IN (normalize_alias(product_names.product_name), normalize_alias(REPLACE(LOWER(product_names.product_name), 'oe', 'ø')))
+        JOIN products
+        ON products.id = product_aliases.product_id
+        WHERE is_active_now(products.active, products.activate_after_timestamp, products.deactivate_after_timestamp)
+        ORDER BY product_aliases.alias_name
+        "#,
+        product_names
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+
+    let mut candidates_by_name: HashMap<String, Vec<ProductSuggestion>> = HashMap::new();
+    let mut exact_matches = HashMap::new();
+    for candidate in alias_candidates {
+        if candidate.exact {
+            exact_matches.insert(candidate.product_name.clone(), candidate.product_id);
+        }
+
+        let candidates = candidates_by_name
+            .entry(candidate.product_name)
+            .or_default();
+        if candidates
+            .iter()
+            .all(|c| c.product_id != candidate.product_id)
+        {
+            candidates.push(ProductSuggestion {
+                product_id: candidate.product_id,
+                product_name: candidate.candidate_name,
+                quickbuy: candidate.alias_name,
+            });
+        }
+    }
+
+    Ok(candidates_by_name
+        .into_iter()
+        .map(|(product_name, candidates)| {
+            let alias_match = match exact_matches.get(&product_name) {
+                Some(product_id) => AliasMatch::Product(*product_id),
+                None if candidates.len() == 1 => AliasMatch::Product(candidates[0].product_id),
+                None => AliasMatch::Ambiguous(candidates),
+            };
+            (product_name, alias_match)
+        })
+        .collect())
+}
+
+async fn get_last_order(
+    user_id: UserId,
+    undo_window: Duration,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Option<LastOrder>, sqlx::Error> {
+    sqlx::query_as!(
+        LastOrder,
+        r#"
+        SELECT
+          id as "id: OrderId",
+          (SELECT COALESCE(SUM(price), 0) FROM sales WHERE order_id = orders.id)::bigint as "price_sum!: StregCents",
+          timestamp > now() - make_interval(secs => $2) as "within_undo_window!",
+          EXISTS(SELECT 1 FROM order_reversals WHERE order_id = orders.id) as "reversed!"
+        FROM orders
+        WHERE user_id = $1
+        ORDER BY timestamp DESC, id DESC
+        LIMIT 1
+        "#,
+        user_id as UserId,
+        undo_window.as_secs_f64()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+}
+
+// The users paying a share of the order, other than the user who made it
+async fn get_order_sharer_ids(
+    order_id: OrderId,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Vec<UserId>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        SELECT user_id as "user_id: UserId"
+        FROM order_shares
+        WHERE order_id = $1
+        "#,
+        order_id as OrderId
+    )
+    .fetch_all(&mut **transaction)
+    .await
+}
+
+async fn reverse_order(
+    user_id: UserId,
+    order: &LastOrder,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<(), sqlx::Error> {
+    let rows_affected = sqlx::query!(
+        r#"
+        INSERT INTO order_reversals(amount, order_id, user_id)
+        VALUES ($1, $2, $3)
+        "#,
+        order.price_sum as StregCents,
+        order.id as OrderId,
+        user_id as UserId
+    )
+    .execute(&mut **transaction)
+    .await?
+    .rows_affected();
+
+    assert_eq!(rows_affected, 1);
+
+    // Units from an undone order are sellable again
+    sqlx::query!(
+        r#"
+        UPDATE products
+        SET stock = products.stock + returned.amount
+        FROM (SELECT product_id, COUNT(*)::int as amount FROM sales WHERE order_id = $1 GROUP BY product_id) AS returned
+        WHERE products.id = returned.product_id AND products.stock IS NOT NULL
+        "#,
+        order.id as OrderId
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+async fn create_order(
+    user_id: UserId,
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<OrderId, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        INSERT INTO orders(user_id)
+        VALUES ($1)
+        RETURNING id as "id: OrderId"
+        "#,
+        user_id as UserId
+    )
+    .fetch_one(&mut **transaction)
+    .await
+}
+
+// Merges products that appear several times in the query for the same consumer,
+// keeping the order of first appearance
+fn get_purchase_lines(
+    priced_products: &[PricedMultiBuyProduct<'_>],
+    user_id: UserId,
+    consumers: &[Consumer],
+) -> Result<Vec<PurchaseLine>, MultiBuyExecutorError> {
+    let mut purchase_lines: Vec<PurchaseLine> = vec![];
+    for priced_product in priced_products {
+        let amount = priced_product.multi_buy_product.amount;
+        let consumer = priced_product.multi_buy_product.consumer.as_ref();
+        let consumer_id = consumer
+            .and_then(|consumer| consumers.iter().find(|c| &c.username == consumer))
+            .map_or(user_id, |c| c.user_id);
+        match purchase_lines
+            .iter_mut()
+            .find(|l| l.product_id == priced_product.product_id && l.consumer_id == consumer_id)
+        {
+            Some(purchase_line) => {
+                purchase_line.amount = purchase_line
+                    .amount
+                    .checked_add(amount.get())
+                    .ok_or(MultiBuyExecutorError::AmountOverflow)?
+            }
+            None => purchase_lines.push(PurchaseLine {
+                product_id: priced_product.product_id,
+                product_name: priced_product.product_name.clone(),
+                category_id: priced_product.category_id,
+                unit_price: priced_product.unit_price,
+                amount,
+                consumer_id,
+                consumer: consumer.cloned(),
+                span: priced_product.multi_buy_product.span,
+                sale_prices: vec![],
+            }),
+        }
+    }
+
+    // Amounts are stored and compared against stock and quotas as i32 in the database
+    if purchase_lines
+        .iter()
+        .any(|l| i32::try_from(l.amount.get()).is_err())
+    {
+        return Err(MultiBuyExecutorError::AmountOverflow);
+    }
+
+    Ok(purchase_lines)
+}
+
+// Bundles are applied across the whole purchase, including products bought for other users
+async fn apply_active_bundles(
+    purchase_lines: &mut [PurchaseLine],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Vec<AppliedBundle>, MultiBuyExecutorError> {
+    let bundles = get_active_bundles(&mut **transaction).await?;
+
+    let bundle_lines = purchase_lines
+        .iter()
+        .map(|l| BundleLine {
+            product_id: l.product_id,
+            category_id: l.category_id,
+            unit_price: l.unit_price,
+            amount: l.amount,
+        })
+        .collect::<Vec<BundleLine>>();
+    let bundled_lines =
+        apply_bundles(&bundle_lines, &bundles).ok_or(MultiBuyExecutorError::StregCentsOverflow)?;
+
+    for (purchase_line, sale_prices) in purchase_lines.iter_mut().zip(bundled_lines.sale_prices) {
+        purchase_line.sale_prices = sale_prices;
+    }
+
+    Ok(bundled_lines.applied_bundles)
+}
+
+// The span to point out when a check fails for a product, optionally only among the lines for one consumer
+fn get_line_span(
+    purchase_lines: &[PurchaseLine],
+    product_id: ProductId,
+    consumer_id: Option<UserId>,
+) -> Span {
+    purchase_lines
+        .iter()
+        .find(|l| l.product_id == product_id && consumer_id.is_none_or(|id| id == l.consumer_id))
+        .map(|l| l.span)
+        .unwrap_or_default()
+}
+
+fn get_product_ids_and_amounts(purchase_lines: &[PurchaseLine]) -> (Vec<ProductId>, Vec<i32>) {
+    purchase_lines
+        .iter()
+        .map(|l| {
+            (
+                l.product_id,
+                i32::try_from(l.amount.get()).expect("purchase line amounts fit in an i32"),
+            )
+        })
+        .unzip()
+}
+
+async fn purchase_products(
+    user_id: UserId,
+    order_id: OrderId,
+    purchase_lines: &[PurchaseLine],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<(), sqlx::Error> {
+    let (product_ids, amounts) = get_product_ids_and_amounts(purchase_lines);
+
+    take_stock(&product_ids, &amounts, transaction).await?;
+
+    let mut sale_product_ids = vec![];
+    let mut sale_amounts = vec![];
+    let mut sale_prices = vec![];
+    let mut consumer_ids = vec![];
+    for purchase_line in purchase_lines {
+        for &(price, amount) in &purchase_line.sale_prices {
+            sale_product_ids.push(purchase_line.product_id);
+            sale_amounts.push(
+                i32::try_from(amount.get()).expect("sale amounts are at most the line amount"),
+            );
+            sale_prices.push(price);
+            consumer_ids.push(purchase_line.consumer_id);
+        }
+    }
+
+    // One sales row is inserted per unit bought, at the price the purchase was checked against
+    // after bundle discounts, so revenue can be summed from the sales
+    let rows_affected = sqlx::query!(
+        r#"
+        INSERT INTO sales(price, product_id, user_id, consumer_id, order_id)
+        SELECT purchases.price, purchases.product_id, $5, purchases.consumer_id, $6
+        FROM UNNEST($1::int[], $2::int[], $3::bigint[], $4::int[]) AS purchases(product_id, amount, price, consumer_id)
+        CROSS JOIN LATERAL generate_series(1, purchases.amount)
+        "#,
+        &sale_product_ids as &[ProductId],
+        &sale_amounts,
+        &sale_prices as &[StregCents],
+        &consumer_ids as &[UserId],
+        user_id as UserId,
+        order_id as OrderId
+    )
+    .execute(&mut **transaction)
+    .await?
+    .rows_affected();
+
+    assert_eq!(
+        rows_affected,
+        amounts.iter().map(|&a| a as u64).sum::<u64>()
+    );
+
+    Ok(())
+}
+
+async fn insert_order_shares(
+    order_id: OrderId,
+    payers: &[Payer],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<(), sqlx::Error> {
+    let (user_ids, shares): (Vec<UserId>, Vec<StregCents>) =
+        payers.iter().map(|p| (p.user_id, p.share)).unzip();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO order_shares(amount, order_id, user_id)
+        SELECT shares.amount, $3, shares.user_id
+        FROM UNNEST($1::int[], $2::bigint[]) AS shares(user_id, amount)
+        "#,
+        &user_ids as &[UserId],
+        &shares as &[StregCents],
+        order_id as OrderId
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+// Quotas apply to the user consuming the products, which is the buyer unless bought for someone else.
+// Daily quotas reset at midnight local time, see local_time_zone().
+async fn check_quotas(
+    username: &str,
+    purchase_lines: &[PurchaseLine],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<(), MultiBuyExecutorError> {
+    let (product_ids, amounts) = get_product_ids_and_amounts(purchase_lines);
+    let consumer_ids = purchase_lines
+        .iter()
+        .map(|l| l.consumer_id)
+        .collect::<Vec<UserId>>();
+
+    // Sales from undone orders do not count towards the quota
+    let rationed_products = sqlx::query!(
+        r#"
+        SELECT
+          products.id as "id: ProductId",
+          products.name,
+          product_quotas.max_amount,
+          product_quotas.period as "period: QuotaPeriod",
+          purchases.amount as "amount!",
+          purchases.consumer_id as "consumer_id!: UserId",
+          (
+            SELECT COUNT(*)
+            FROM sales
+            WHERE sales.consumer_id = purchases.consumer_id AND sales.product_id = products.id
+              AND NOT EXISTS(SELECT 1 FROM order_reversals WHERE order_reversals.order_id = sales.order_id)
+              AND CASE product_quotas.period
+                WHEN 'day' THEN sales.timestamp >= date_trunc('day', now(), local_time_zone())
+                WHEN 'event' THEN sales.timestamp >= product_quotas.starts_at AND sales.timestamp < product_quotas.ends_at
+                ELSE true
+              END
+          )::int as "already_bought!"
+        FROM UNNEST($1::int[], $2::int[], $3::int[]) AS purchases(product_id, amount, consumer_id)
+        JOIN products
+        ON products.id = purchases.product_id
+        JOIN product_quotas
+        ON product_quotas.product_id = products.id
+        WHERE product_quotas.period != 'event' OR (product_quotas.starts_at <= now() AND product_quotas.ends_at > now())
+        ORDER BY products.id, purchases.consumer_id
+        "#,
+        &product_ids as &[ProductId],
+        &amounts,
+        &consumer_ids as &[UserId]
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+
+    for product in rationed_products {
+        let remaining = (product.max_amount - product.already_bought).max(0);
+        if product.amount > remaining {
+            let consumer = purchase_lines
+                .iter()
+                .find(|l| l.consumer_id == product.consumer_id)
+                .and_then(|l| l.consumer.as_deref())
+                .unwrap_or(username);
+            return Err(MultiBuyExecutorError::QuotaExceeded {
+                username: consumer.to_string(),
+                product_name: product.name,
+                max_amount: product.max_amount,
+                period: product.period,
+                remaining,
+                span: get_line_span(purchase_lines, product.id, Some(product.consumer_id)),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Locking the stock limited products keeps concurrent purchases from selling the same units
+async fn lock_stock_limited_products(
+    product_ids: &[ProductId],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        SELECT id
+        FROM products
+        WHERE id = ANY($1) AND stock IS NOT NULL
+        ORDER BY id
+        FOR UPDATE
+        "#,
+        product_ids as &[ProductId]
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+async fn check_stock(
+    purchase_lines: &[PurchaseLine],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<(), MultiBuyExecutorError> {
+    let (product_ids, amounts) = get_product_ids_and_amounts(purchase_lines);
+
+    let stock_limited_products = sqlx::query!(
+        r#"
+        SELECT products.id as "id: ProductId", products.name, products.stock as "stock!", purchases.amount as "amount!"
+        FROM (
+            -- The same product can be bought for several consumers
+            SELECT product_id, SUM(amount)::int as amount
+            FROM UNNEST($1::int[], $2::int[]) AS lines(product_id, amount)
+            GROUP BY product_id
+        ) AS purchases
+        JOIN products
+        ON products.id = purchases.product_id
+        WHERE products.stock IS NOT NULL
+        ORDER BY products.id
+        "#,
+        &product_ids as &[ProductId],
+        &amounts
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+
+    if let Some(product) = stock_limited_products
+        .into_iter()
+        .find(|p| p.stock < p.amount)
+    {
+        return Err(MultiBuyExecutorError::OutOfStock {
+            product_name: product.name,
+            available: product.stock,
+            span: get_line_span(purchase_lines, product.id, None),
+        });
+    }
+
+    Ok(())
+}
+
+async fn take_stock(
+    product_ids: &[ProductId],
+    amounts: &[i32],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE products
+        SET stock = products.stock - purchases.amount
+        FROM (
+            SELECT product_id, SUM(amount)::int as amount
+            FROM UNNEST($1::int[], $2::int[]) AS lines(product_id, amount)
+            GROUP BY product_id
+        ) AS purchases
+        WHERE products.id = purchases.product_id AND products.stock IS NOT NULL
+        "#,
+        product_ids as &[ProductId],
+        amounts
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(())
+}
+
+#[serde_as]
+#[derive(Error, Debug, Serialize)]
+#[serde(tag = "type", content = "context")]
+pub enum MultiBuyExecutorError {
+    #[error("database error: {0}")]
+    DbError(
+        #[serde_as(as = "DisplayFromStr")]
+        #[from]
+        sqlx::Error,
+    ),
+
+    #[error("invalid username: {username}")]
+    InvalidUsername {
+        username: String,
+        suggestions: Vec<String>,
+    },
+
+    #[error("invalid product: {product_name}")]
+    InvalidProduct {
+        product_name: String,
+        span: Span,
+        suggestions: Vec<ProductSuggestion>,
+    },
+
+    #[error("product {product_name} matches several products")]
+    AmbiguousProduct {
+        product_name: String,
+        span: Span,
+        candidates: Vec<ProductSuggestion>,
+    },
+
+    #[error("user {username} has insufficient funds to pay for: {product_price_sum}, remaining credit: {remaining_credit}")]
+    InsufficientFunds {
+        username: String,
+        product_price_sum: StregCents,
+        remaining_credit: StregCents,
+    },
+
+    #[error("stregcents overflow / underflow")]
+    StregCentsOverflow,
+
+    #[error("amount overflow")]
+    AmountOverflow,
+
+    #[error("quickbuy macro {macro_name} can't be expanded")]
+    InvalidMacro { macro_name: String },
+
+    #[error("user {0} has no recent purchase to undo")]
+    NoPurchaseToUndo(String),
+
+    #[error("user {0} has no purchase to repeat")]
+    NoPurchaseToRepeat(String),
+
+    #[error("product {product_name} can no longer be bought")]
+    ProductUnavailable { product_name: String },
+
+    #[error("user {username} is not old enough to buy {product_name}")]
+    AgeRestricted {
+        username: String,
+        product_name: String,
+        span: Span,
+    },
+
+    #[error("product {product_name} only has {available} left in stock")]
+    OutOfStock {
+        product_name: String,
+        available: i32,
+        span: Span,
+    },
+
+    #[error(
+        "product {product_name} is limited to {max_amount} per {period:?}, {remaining} remaining for {username}"
+    )]
+    QuotaExceeded {
+        username: String,
+        product_name: String,
+        max_amount: i32,
+        period: QuotaPeriod,
+        remaining: i32,
+        span: Span,
+    },
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ProductSuggestion {
+    pub product_id: ProductId,
+    pub product_name: String,
+    // What to type in the quickbuy to buy the suggested product
+    pub quickbuy: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RowLocks {
+    // Purchases lock the rows they check, so concurrent purchases can't both pass the checks
+    Take,
+    // Previews only read, so previewing never blocks or waits for purchases at other terminals
+    Skip,
+}
+
+enum AliasMatch {
+    Product(ProductId),
+    Ambiguous(Vec<ProductSuggestion>),
+}
+
+struct LastOrder {
+    id: OrderId,
+    price_sum: StregCents,
+    within_undo_window: bool,
+    reversed: bool,
+}
+
+struct MultiBuyProductProductIdPair<'a> {
+    multi_buy_product: &'a MultiBuyProduct,
+    product_id: ProductId,
+}
+
+struct ProductPrice {
+    name: String,
+    category_id: Option<CategoryId>,
+    price: StregCents,
+}
+
+struct PricedMultiBuyProduct<'a> {
+    multi_buy_product: &'a MultiBuyProduct,
+    product_id: ProductId,
+    product_name: String,
+    category_id: Option<CategoryId>,
+    unit_price: StregCents,
+}
+
+struct PurchaseLine {
+    product_id: ProductId,
+    product_name: String,
+    category_id: Option<CategoryId>,
+    // The price before bundle discounts
+    unit_price: StregCents,
+    amount: NonZeroU32,
+    consumer_id: UserId,
+    consumer: Option<String>,
+    // Where the first product merged into the line is in the query
+    span: Span,
+    // The prices the units are sold at after bundle discounts, adding up to amount units
+    sale_prices: Vec<(StregCents, NonZeroU32)>,
+}
+
+struct Consumer {
+    username: String,
+    user_id: UserId,
+}
+
+pub struct MultiBuyResult {
+    pub order_id: OrderId,
+    pub bought_products: Vec<BoughtProduct>,
+    // What is paid in total, after bundle discounts
+    pub product_price_sum: StregCents,
+    // The balance of the user making the purchase
+    pub new_user_balance: StregCents,
+    pub shares: Vec<PaymentShare>,
+    pub discounts: Vec<AppliedDiscount>,
+}
+
+pub struct MultiBuyPreview {
+    pub products: Vec<PreviewedProduct>,
+    pub product_price_sum: StregCents,
+    pub new_user_balance: StregCents,
+    pub shares: Vec<PaymentShare>,
+    pub discounts: Vec<AppliedDiscount>,
+}
+
+struct RepeatableOrder {
+    order_id: OrderId,
+    // The users who split the order with the user, in the order they were listed
+    split_with: Vec<String>,
+    products: Vec<MultiBuyProduct>,
+}
+
+struct PreparedMultiBuy<'a> {
+    user_id: UserId,
+    // The user making the purchase comes first, followed by the users splitting the cost with them
+    payers: Vec<Payer>,
+    priced_products: Vec<PricedMultiBuyProduct<'a>>,
+    purchase_lines: Vec<PurchaseLine>,
+    // What is paid, after bundle discounts
+    product_price_sum: StregCents,
+    applied_bundles: Vec<AppliedBundle>,
+}
+
+struct Payer {
+    username: String,
+    user_id: UserId,
+    share: StregCents,
+    new_user_balance: StregCents,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quickbuy::parser::multi_buy_products;
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_buy_product_by_id(pool: PgPool) {
+        let product = MultiBuyProduct::new("1", 1);
+
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_buy_product_by_alias(pool: PgPool) {
+        let product = MultiBuyProduct::new("enabled", 1);
+
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_buy_product_by_alias_case_insensitive(pool: PgPool) {
+        let product = MultiBuyProduct::new("eNaBlEd", 1);
+
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_case_insensitive_username(pool: PgPool) {
+        let product = MultiBuyProduct::new("enabled", 1);
+
+        execute_multi_buy_query("TeSt_UsEr", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_multiple_products(pool: PgPool) {
+        let products = [
+            MultiBuyProduct::new("enabled", 2),
+            MultiBuyProduct::new("2", 1),
+            MultiBuyProduct::new("active", 1),
+        ];
+
+        let MultiBuyResult {
+            bought_products,
+            product_price_sum,
+            new_user_balance,
+            ..
+        } = execute_multi_buy_query("test_user", &[], &products, true, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(bought_products.len(), 2);
+        assert_eq!(bought_products[0].product_id, "1".parse().unwrap());
+        assert_eq!(bought_products[0].product_name, "Enabled");
+        assert_eq!(bought_products[0].amount, 3);
+        assert_eq!(bought_products[0].unit_price, "7.00");
+        assert_eq!(bought_products[0].line_total, "21.00");
+        assert_eq!(bought_products[1].product_id, "2".parse().unwrap());
+        assert_eq!(bought_products[1].product_name, "No aliases");
+        assert_eq!(bought_products[1].amount, 1);
+        assert_eq!(bought_products[1].line_total, "12.00");
+        assert_eq!(product_price_sum.to_string(), "33.00");
+        assert_eq!(new_user_balance.to_string(), "67.00");
+
+        let sales_count = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM sales"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sales_count, 4);
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_creates_one_order_per_query(pool: PgPool) {
+        let products = [
+            MultiBuyProduct::new("enabled", 2),
+            MultiBuyProduct::new("2", 1),
+        ];
+
+        let MultiBuyResult {
+            order_id: first_order_id,
+            ..
+        } = execute_multi_buy_query("test_user", &[], &products, true, &pool)
+            .await
+            .unwrap();
+        let MultiBuyResult {
+            order_id: second_order_id,
+            ..
+        } = execute_multi_buy_query("test_user", &[], &products, true, &pool)
+            .await
+            .unwrap();
+
+        assert_ne!(first_order_id, second_order_id);
+
+        let first_order_sales_count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) as "count!" FROM sales WHERE order_id = $1"#,
+            first_order_id as OrderId
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(first_order_sales_count, 3);
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn preview_multi_buy(pool: PgPool) {
+        let products = [
+            MultiBuyProduct::new("enabled", 2),
+            MultiBuyProduct::new("limited", 1),
+        ];
+
+        let MultiBuyPreview {
+            products: previewed_products,
+            product_price_sum,
+            new_user_balance,
+            ..
+        } = preview_multi_buy_query("test_user", &[], &products, true, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(previewed_products.len(), 2);
+        assert_eq!(previewed_products[0].product_name, "Enabled");
+        assert_eq!(previewed_products[0].unit_price, "7.00");
+        assert_eq!(previewed_products[0].line_total, "14.00");
+        assert_eq!(previewed_products[1].product_name, "Limited");
+        assert_eq!(previewed_products[1].line_total, "5.00");
+        assert_eq!(product_price_sum.to_string(), "19.00");
+        assert_eq!(new_user_balance.to_string(), "81.00");
+
+        // Nothing is bought
+        let sales_count = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM sales"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sales_count, 0);
+        let stock = sqlx::query_scalar!("SELECT stock FROM products WHERE id = 7")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stock, Some(2));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn preview_multi_buy_insufficient_funds(pool: PgPool) {
+        let product = MultiBuyProduct::new("expensive", 1);
+        let result = preview_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::InsufficientFunds { .. })
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn preview_multi_buy_takes_no_locks(pool: PgPool) {
+        // A purchase in progress holds the locks on the user and the stock limited product
+        let user_id = get_user_id_by_name("test_user", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut purchase_transaction = pool.begin().await.unwrap();
+        lock_users_by_ids(&[user_id], &mut purchase_transaction)
+            .await
+            .unwrap();
+        lock_stock_limited_products(&["7".parse().unwrap()], &mut purchase_transaction)
+            .await
+            .unwrap();
+
+        let preview = tokio::time::timeout(
+            Duration::from_secs(5),
+            preview_multi_buy_query(
+                "test_user",
+                &[],
+                &multi_buy_products("test_user limited"),
+                true,
+                &pool,
+            ),
+        )
+        .await
+        .expect("preview should not wait for the purchase");
+
+        assert!(preview.is_ok());
+        purchase_transaction.rollback().await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn multi_buy_invalid_username(pool: PgPool) {
+        let result = execute_multi_buy_query("i_do_not_exist", &[], &[], true, &pool).await;
+
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::InvalidUsername { username, .. }) if username == "i_do_not_exist")
+        );
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/users.sql"))]
+    async fn multi_buy_invalid_username_suggestions(pool: PgPool) {
+        let result = execute_multi_buy_query("tset_user", &[], &[], true, &pool).await;
+
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::InvalidUsername { suggestions, .. }) if suggestions == ["test_user"])
+        );
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/users.sql"))]
+    async fn multi_buy_invalid_username_suggestions_disabled(pool: PgPool) {
+        let result = execute_multi_buy_query("tset_user", &[], &[], false, &pool).await;
+
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::InvalidUsername { suggestions, .. }) if suggestions.is_empty())
+        );
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/users.sql"))]
+    async fn username_suggestions_prefix_first(pool: PgPool) {
+        let suggestions = get_username_suggestions("t", &pool).await.unwrap();
+
+        assert_eq!(suggestions, ["test_user", "trusted_user"]);
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/users.sql"))]
+    async fn multi_buy_invalid_product_unknown(pool: PgPool) {
+        let product = MultiBuyProduct::new("1337", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::InvalidProduct { product_name, .. }) if product_name == "1337")
+        );
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_alias_without_diacritics(pool: PgPool) {
+        let mut transaction = pool.begin().await.unwrap();
+        let product_names = ["ol", "OEL", "Øl", "cafe", "sodavand", "sødavand"].map(String::from);
+
+        let product_ids = get_product_ids_by_aliases(&product_names, &mut transaction)
+            .await
+            .unwrap();
+
+        let expected = ["11", "11", "11", "12", "13", "14"];
+        for (product_name, expected) in product_names.iter().zip(expected) {
+            let expected = expected.parse::<ProductId>().unwrap();
+            assert!(
+                matches!(product_ids.get(product_name), Some(AliasMatch::Product(id)) if *id == expected),
+                "{product_name} should match product {expected:?}"
+            );
+        }
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_alias_containing_oe_is_not_collapsed(pool: PgPool) {
+        sqlx::query!(
+            "INSERT INTO product_aliases(alias_name, product_id) VALUES ('coed', 1), ('cod', 5)"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let mut transaction = pool.begin().await.unwrap();
+        let product_names = ["coed", "cod"].map(String::from);
+
+        let product_ids = get_product_ids_by_aliases(&product_names, &mut transaction)
+            .await
+            .unwrap();
+
+        let expected = ["1", "5"];
+        for (product_name, expected) in product_names.iter().zip(expected) {
+            let expected = expected.parse::<ProductId>().unwrap();
+            assert!(
+                matches!(product_ids.get(product_name), Some(AliasMatch::Product(id)) if *id == expected),
+                "{product_name} should match product {expected:?}"
+            );
+        }
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/products.sql"))]
+    async fn aliases_must_be_parsable_as_product_names(pool: PgPool) {
+        for alias_name in ["4x4", "2xl", "21", "cola*", "@cola"] {
+            let result = sqlx::query!(
+                "INSERT INTO product_aliases(alias_name, product_id) VALUES ($1, 1)",
+                alias_name
+            )
+            .execute(&pool)
+            .await;
+
+            assert!(result.is_err(), "{alias_name} should be rejected");
+        }
+
+        for alias_name in ["x2", "7up", "cola@"] {
+            sqlx::query!(
+                "INSERT INTO product_aliases(alias_name, product_id) VALUES ($1, 1)",
+                alias_name
+            )
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_alias_ignores_inactive_products(pool: PgPool) {
+        // Product 3 is inactive, so øl must keep matching only product 11
+        sqlx::query!("INSERT INTO product_aliases(alias_name, product_id) VALUES ('ol', 3)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut transaction = pool.begin().await.unwrap();
+        let product_names = ["øl".to_string()];
+
+        let product_ids = get_product_ids_by_aliases(&product_names, &mut transaction)
+            .await
+            .unwrap();
+
+        let expected = "11".parse::<ProductId>().unwrap();
+        assert!(matches!(
+            product_ids.get("øl"),
+            Some(AliasMatch::Product(id)) if *id == expected
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_ambiguous_alias(pool: PgPool) {
+        let product = MultiBuyProduct::new("soedavand", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        let Err(MultiBuyExecutorError::AmbiguousProduct { candidates, .. }) = result else {
+            panic!("expected ambiguous product");
+        };
+        let candidates = candidates
+            .iter()
+            .map(|c| c.quickbuy.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(candidates, ["sodavand", "sødavand"]);
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_ambiguous_alias_skips_inactive(pool: PgPool) {
+        sqlx::query!("UPDATE products SET active = false WHERE id = 14")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let product = MultiBuyProduct::new("soedavand", 1);
+
+        let MultiBuyResult {
+            bought_products, ..
+        } = execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(bought_products[0].product_name, "Sodavand");
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_invalid_product_span(pool: PgPool) {
+        let products = multi_buy_products("test_user øl:2 nope");
+        let result = execute_multi_buy_query("test_user", &[], &products, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::InvalidProduct { span, .. }) if span.byte_start == 16 && span.char_start == 15
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_invalid_product_suggestions(pool: PgPool) {
+        let product = MultiBuyProduct::new("Limted", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        let Err(MultiBuyExecutorError::InvalidProduct { suggestions, .. }) = result else {
+            panic!("expected invalid product");
+        };
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].product_name, "Limited");
+        assert_eq!(suggestions[0].quickbuy, "limited");
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_invalid_product_suggestions_skip_inactive(pool: PgPool) {
+        let product = MultiBuyProduct::new("inactiv", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::InvalidProduct { suggestions, .. }) if suggestions.is_empty()
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_invalid_product_inactive(pool: PgPool) {
+        let product = MultiBuyProduct::new("inactive", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::InvalidProduct { product_name, .. }) if product_name == "inactive")
+        );
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_invalid_product_deactivated_by_timestamp(pool: PgPool) {
+        let product = MultiBuyProduct::new("inactive_timestamp", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::InvalidProduct { product_name, .. }) if product_name == "inactive_timestamp")
+        );
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_ignores_scheduled_price(pool: PgPool) {
+        sqlx::query!("SELECT schedule_product_price(1, 100000, now() + interval '1 day')")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        let MultiBuyResult {
+            product_price_sum, ..
+        } = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(product_price_sum.to_string(), "7.00");
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_charges_changed_price(pool: PgPool) {
+        sqlx::query!("SELECT schedule_product_price(1, 800, now() - interval '1 hour')")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        let MultiBuyResult {
+            product_price_sum, ..
+        } = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled:2"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(product_price_sum.to_string(), "16.00");
+        let sale_prices = sqlx::query_scalar!("SELECT price FROM sales")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sale_prices, [800, 800]);
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql",
+        "../../fixtures/product_categories.sql",
+        "../../fixtures/pricing_rules.sql"
+    ))]
+    async fn multi_buy_charges_discounted_price(pool: PgPool) {
+        let MultiBuyResult {
+            bought_products,
+            product_price_sum,
+            ..
+        } = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled sodavand:2"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        let unit_prices = bought_products
+            .iter()
+            .map(|p| p.unit_price.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(unit_prices, ["5.00", "9.35"]);
+        assert_eq!(product_price_sum.to_string(), "23.70");
+
+        // Sales record the price that was charged
+        let sale_prices = sqlx::query_scalar!("SELECT price FROM sales ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sale_prices, [500, 935, 935]);
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql",
+        "../../fixtures/product_categories.sql",
+        "../../fixtures/bundles.sql"
+    ))]
+    async fn multi_buy_applies_bundles(pool: PgPool) {
+        let MultiBuyResult {
+            bought_products,
+            product_price_sum,
+            new_user_balance,
+            discounts,
+            ..
+        } = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user sodavand:2 øl sødavand café enabled"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        // The products are listed at their regular prices with the discounts itemized
+        assert_eq!(bought_products.len(), 5);
+        assert_eq!(bought_products[0].line_total, "22.00");
+        let discounts = discounts
+            .iter()
+            .map(|d| (d.name.as_str(), d.times, d.discount.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            discounts,
+            [
+                ("3 sodavand for 20 kr", 1, "13.00"),
+                ("Øl og café for 20 kr", 1, "5.00")
+            ]
+        );
+        assert_eq!(product_price_sum.to_string(), "47.00");
+        assert_eq!(new_user_balance.to_string(), "53.00");
+
+        // Sales record the effective price of each unit, so they add up to what was paid
+        let sale_prices = sqlx::query_scalar!("SELECT price FROM sales ORDER BY product_id, price")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sale_prices, [700, 800, 1200, 667, 667, 666]);
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql",
+        "../../fixtures/product_categories.sql",
+        "../../fixtures/bundles.sql"
+    ))]
+    async fn preview_multi_buy_applies_bundles(pool: PgPool) {
+        let MultiBuyPreview {
+            product_price_sum,
+            discounts,
+            ..
+        } = preview_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user sodavand:3 enabled"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        // The inactive bundle makes enabled free, but must not be applied
+        assert_eq!(discounts.len(), 1);
+        assert_eq!(product_price_sum.to_string(), "27.00");
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_invalid_product_without_price(pool: PgPool) {
+        sqlx::query!("UPDATE product_prices SET valid_until = now() WHERE product_id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let result = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled"),
+            true,
+            &pool,
+        )
+        .await;
+
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::InvalidProduct { product_name, .. }) if product_name == "enabled")
+        );
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/products.sql"))]
+    async fn schedule_price_at_scheduled_price(pool: PgPool) {
+        let first_price_id =
+            sqlx::query_scalar!("SELECT schedule_product_price(1, 900, '2999-01-01')")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let second_price_id =
+            sqlx::query_scalar!("SELECT schedule_product_price(1, 800, '2999-01-01')")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+
+        assert_eq!(first_price_id, second_price_id);
+
+        let prices = sqlx::query!(
+            r#"
+            SELECT price, valid_until::text
+            FROM product_prices
+            WHERE product_id = 1
+            ORDER BY valid_from
+            "#
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|p| (p.price, p.valid_until))
+        .collect::<Vec<_>>();
+
+        assert_eq!(prices.len(), 2);
+        assert_eq!(prices[0].0, 700);
+        assert!(prices[0].1.as_deref().unwrap().starts_with("2999-01-01"));
+        assert_eq!(prices[1], (800, None));
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/products.sql"))]
+    async fn schedule_price_before_scheduled_price(pool: PgPool) {
+        sqlx::query!("SELECT schedule_product_price(1, 900, '2999-01-02')")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        sqlx::query!("SELECT schedule_product_price(1, 800, '2999-01-01')")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        let prices = sqlx::query!(
+            r#"
+            SELECT price, valid_from::text as "valid_from!", valid_until::text
+            FROM product_prices
+            WHERE product_id = 1
+            ORDER BY valid_from
+            "#
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|p| (p.price, p.valid_from, p.valid_until))
+        .collect::<Vec<_>>();
+
+        assert_eq!(prices.len(), 3);
+        assert_eq!(prices[0].0, 700);
+        assert!(prices[0].2.as_deref().unwrap().starts_with("2999-01-01"));
+        assert_eq!(prices[1].0, 800);
+        assert!(prices[1].2.as_deref().unwrap().starts_with("2999-01-02"));
+        assert_eq!(prices[2].0, 900);
+        assert_eq!(prices[2].2, None);
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_invalid_product_not_yet_activated(pool: PgPool) {
+        let product = MultiBuyProduct::new("scheduled", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::InvalidProduct { product_name, .. }) if product_name == "scheduled")
+        );
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_activated_by_timestamp(pool: PgPool) {
+        let product = MultiBuyProduct::new("active_timestamp", 1);
+
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_insufficient_funds_no_money(pool: PgPool) {
+        let product = MultiBuyProduct::new("enabled", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::InsufficientFunds { .. })
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_insufficient_funds_too_expensive(pool: PgPool) {
+        let product = MultiBuyProduct::new("expensive", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::InsufficientFunds { .. })
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_within_credit_limit(pool: PgPool) {
+        let product = MultiBuyProduct::new("enabled", 1);
+
+        let MultiBuyResult {
+            new_user_balance, ..
+        } = execute_multi_buy_query("trusted_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+
+        assert_eq!(new_user_balance.to_string(), "-7.00");
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_insufficient_funds_exceeds_credit_limit(pool: PgPool) {
+        let product = MultiBuyProduct::new("enabled", 8);
+        let result = execute_multi_buy_query("trusted_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::InsufficientFunds { product_price_sum, remaining_credit, .. })
+                if product_price_sum.to_string() == "56.00" && remaining_credit.to_string() == "50.00"
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_decrements_stock(pool: PgPool) {
+        let product = MultiBuyProduct::new("limited", 2);
+
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+
+        let stock = sqlx::query_scalar!("SELECT stock FROM products WHERE id = 7")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stock, Some(0));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_out_of_stock(pool: PgPool) {
+        let products = [
+            MultiBuyProduct::new("enabled", 1),
+            MultiBuyProduct::new("limited", 3),
+        ];
+        let result = execute_multi_buy_query("test_user", &[], &products, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::OutOfStock { product_name, available, .. })
+                if product_name == "Limited" && available == 2
+        ));
+
+        let sales_count = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM sales"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sales_count, 0);
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_sold_out(pool: PgPool) {
+        let product = MultiBuyProduct::new("sold_out", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::OutOfStock { available: 0, .. })
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/product_quotas.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_quota_exceeded(pool: PgPool) {
+        let product = MultiBuyProduct::new("rationed", 1);
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+
+        let product = MultiBuyProduct::new("rationed", 2);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::QuotaExceeded { username, product_name, max_amount: 2, period: QuotaPeriod::Day, remaining: 1, .. })
+                if username == "test_user" && product_name == "Rationed"
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/product_quotas.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_daily_quota_starts_at_local_midnight(pool: PgPool) {
+        let product = MultiBuyProduct::new("rationed", 2);
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+        // Just after midnight in Copenhagen is still the previous day in UTC
+        sqlx::query!(
+            "UPDATE sales SET timestamp = date_trunc('day', now(), local_time_zone()) + interval '1 second'"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let product = MultiBuyProduct::new("rationed", 1);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::QuotaExceeded { remaining: 0, .. })
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/product_quotas.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_quota_counts_split_tokens(pool: PgPool) {
+        let products = [
+            MultiBuyProduct::new("rationed", 2),
+            MultiBuyProduct::new("9", 1),
+        ];
+        let result = execute_multi_buy_query("test_user", &[], &products, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::QuotaExceeded { remaining: 2, .. })
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/product_quotas.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_quota_ignores_undone_orders(pool: PgPool) {
+        let product = MultiBuyProduct::new("rationed", 2);
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+        execute_undo_query("test_user", Duration::from_secs(60), true, &pool)
+            .await
+            .unwrap();
+
+        let product = MultiBuyProduct::new("rationed", 2);
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_age_restricted_adult(pool: PgPool) {
+        let product = MultiBuyProduct::new("restricted", 1);
+
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_age_restricted_underage(pool: PgPool) {
+        let product = MultiBuyProduct::new("restricted", 1);
+        let result = execute_multi_buy_query("minor_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::AgeRestricted { username, product_name, .. })
+                if username == "minor_user" && product_name == "Restricted"
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_age_restricted_eighteenth_birthday(pool: PgPool) {
+        sqlx::query!(
+            "UPDATE users SET birth_date = (now() AT TIME ZONE local_time_zone())::date - interval '18 years' WHERE username = 'minor_user'"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let product = MultiBuyProduct::new("restricted", 1);
+
+        execute_multi_buy_query("minor_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/product_quotas.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_check_errors_point_at_the_product(pool: PgPool) {
+        let result = execute_multi_buy_query(
+            "minor_user",
+            &[],
+            &multi_buy_products("minor_user enabled restricted"),
+            true,
+            &pool,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::AgeRestricted { span, .. }) if span.byte_start == 19 && span.byte_end == 29
+        ));
+
+        let result = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled rationed:3"),
+            true,
+            &pool,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::QuotaExceeded { span, .. }) if span.byte_start == 18
+        ));
+
+        let result = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled limited:3"),
+            true,
+            &pool,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::OutOfStock { span, .. }) if span.byte_start == 18
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_age_restricted_no_birth_date(pool: PgPool) {
+        let product = MultiBuyProduct::new("restricted", 1);
+        let result = execute_multi_buy_query("trusted_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::AgeRestricted { .. })
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn multi_buy_streg_cents_overflow(pool: PgPool) {
+        let product = MultiBuyProduct::new("overflow", i32::MAX as u32);
+        let result = execute_multi_buy_query("test_user", &[], &[product], true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::StregCentsOverflow)
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_merged_amount_overflow(pool: PgPool) {
+        let result = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled:4000000000 enabled:4000000000"),
+            true,
+            &pool,
+        )
+        .await;
+
+        assert!(matches!(result, Err(MultiBuyExecutorError::AmountOverflow)));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_free_product_amount_overflow(pool: PgPool) {
+        sqlx::query!("SELECT schedule_product_price(1, 0, now())")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        let result = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled:3000000000"),
+            true,
+            &pool,
+        )
+        .await;
+
+        assert!(matches!(result, Err(MultiBuyExecutorError::AmountOverflow)));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_concurrent_purchases_cannot_overdraw(pool: PgPool) {
+        // Each purchase costs 70 kr and test_user has 100 kr, so only one may succeed
+        let handles = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let product = MultiBuyProduct::new("enabled", 10);
+                    execute_multi_buy_query("test_user", &[], &[product], true, &pool).await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut successful_purchases = 0;
+        for handle in handles {
+            match handle.await.unwrap() {
+                Ok(_) => successful_purchases += 1,
+                Err(MultiBuyExecutorError::InsufficientFunds { .. }) => {}
+                Err(e) => panic!("unexpected error: {e}"),
+            }
+        }
+
+        assert_eq!(successful_purchases, 1);
+
+        let user_id = get_user_id_by_name("test_user", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut transaction = pool.begin().await.unwrap();
+        let user_balance = get_user_balance_by_id(user_id, &mut transaction)
+            .await
+            .unwrap();
+        assert_eq!(user_balance.to_string(), "30.00");
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn undo_last_purchase(pool: PgPool) {
+        let product = MultiBuyProduct::new("enabled", 10);
+        let MultiBuyResult { order_id, .. } =
+            execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+                .await
+                .unwrap();
+
+        let (undone_order_id, refunded_price_sum, new_user_balance) =
+            execute_undo_query("test_user", Duration::from_secs(60), true, &pool)
+                .await
+                .unwrap();
+
+        assert_eq!(undone_order_id, order_id);
+        assert_eq!(refunded_price_sum.to_string(), "70.00");
+        assert_eq!(new_user_balance.to_string(), "100.00");
+
+        // The sales are kept for auditing
+        let sales_count = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM sales"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sales_count, 10);
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn undo_returns_stock(pool: PgPool) {
+        let product = MultiBuyProduct::new("limited", 2);
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+
+        execute_undo_query("test_user", Duration::from_secs(60), true, &pool)
+            .await
+            .unwrap();
+
+        let stock = sqlx::query_scalar!("SELECT stock FROM products WHERE id = 7")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stock, Some(2));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn undo_only_once(pool: PgPool) {
+        let product = MultiBuyProduct::new("enabled", 1);
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+
+        execute_undo_query("test_user", Duration::from_secs(60), true, &pool)
+            .await
+            .unwrap();
+        let result = execute_undo_query("test_user", Duration::from_secs(60), true, &pool).await;
+
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::NoPurchaseToUndo(username)) if username == "test_user")
+        );
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn undo_outside_window(pool: PgPool) {
+        let product = MultiBuyProduct::new("enabled", 1);
+        execute_multi_buy_query("test_user", &[], &[product], true, &pool)
+            .await
+            .unwrap();
+
+        let result = execute_undo_query("test_user", Duration::ZERO, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::NoPurchaseToUndo(_))
+        ));
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/users.sql"))]
+    async fn undo_without_purchases(pool: PgPool) {
+        let result = execute_undo_query("test_user", Duration::from_secs(60), true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::NoPurchaseToUndo(_))
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn repeat_last_purchase(pool: PgPool) {
+        let products = multi_buy_products("test_user enabled @trusted_user:rationed:2 enabled");
+        let MultiBuyResult { order_id, .. } =
+            execute_multi_buy_query("test_user", &[], &products, true, &pool)
+                .await
+                .unwrap();
+
+        // The current price is charged, not the one the order was made at
+        sqlx::query!("SELECT schedule_product_price(1, 800, now())")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        let (
+            repeated_order_id,
+            MultiBuyResult {
+                order_id: new_order_id,
+                bought_products,
+                product_price_sum,
+                new_user_balance,
+                ..
+            },
+        ) = execute_repeat_query("test_user", true, &pool)
+            .await
+            .unwrap();
 
-    for multi_buy_product_with_id in mutli_buy_products_with_ids {
-        products_with_prices.push((
-            multi_buy_product_with_id,
-            get_product_price(multi_buy_product_with_id, transaction).await?,
-        ))
+        assert_eq!(repeated_order_id, order_id);
+        assert_ne!(new_order_id, order_id);
+        let bought_products = bought_products
+            .iter()
+            .map(|p| {
+                (
+                    p.product_name.as_str(),
+                    p.amount,
+                    p.consumer.as_deref(),
+                    p.unit_price.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bought_products,
+            [
+                ("Enabled", 2, None, "8.00"),
+                ("Rationed", 2, Some("trusted_user"), "1.00")
+            ]
+        );
+        assert_eq!(product_price_sum.to_string(), "18.00");
+        assert_eq!(new_user_balance.to_string(), "66.00");
     }
 
-    stregcents_sum(
-        products_with_prices
-            .into_iter()
-            .map(|(p, price)| price * p.multi_buy_product.amount),
-    )
-    .ok_or(MultiBuyExecutorError::StregCentsOverflow)
-}
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn repeat_skips_undone_orders(pool: PgPool) {
+        execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+        execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user rationed"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+        execute_undo_query("test_user", Duration::from_secs(60), true, &pool)
+            .await
+            .unwrap();
 
-async fn get_product_price(
-    multi_buy_product_with_id: &MultiBuyProductProductIdPair<'_>,
-    transaction: &mut Transaction<'static, Postgres>,
-) -> Result<StregCents, MultiBuyExecutorError> {
-    get_product_price_by_id(multi_buy_product_with_id.product_id, transaction)
-        .await?
-        .ok_or_else(|| {
-            MultiBuyExecutorError::InvalidProduct(
-                multi_buy_product_with_id
-                    .multi_buy_product
-                    .product_name
-                    .clone(),
-            )
-        })
-}
+        let (
+            _,
+            MultiBuyResult {
+                bought_products, ..
+            },
+        ) = execute_repeat_query("test_user", true, &pool)
+            .await
+            .unwrap();
 
-async fn get_product_price_by_id(
-    product_id: ProductId,
-    transaction: &mut Transaction<'static, Postgres>,
-) -> Result<Option<StregCents>, sqlx::Error> {
-    sqlx::query_scalar!(
-        r#"
-        SELECT price as "price: StregCents"
-        FROM products
-        WHERE id = $1 AND active=true AND (deactivate_after_timestamp IS NULL OR deactivate_after_timestamp > now())
-        "#,
-        product_id as ProductId
-    )
-    .fetch_optional(&mut **transaction)
-    .await
-}
+        assert_eq!(bought_products.len(), 1);
+        assert_eq!(bought_products[0].product_name, "Enabled");
+    }
 
-async fn get_multi_buy_products_with_ids<'a>(
-    multi_buy_products: &'a [MultiBuyProduct],
-    transaction: &mut Transaction<'static, Postgres>,
-) -> Result<Vec<MultiBuyProductProductIdPair<'a>>, MultiBuyExecutorError> {
-    let mut products_with_ids: Vec<MultiBuyProductProductIdPair<'a>> = vec![];
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn repeat_deactivated_product(pool: PgPool) {
+        execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+        sqlx::query!("UPDATE products SET active = false WHERE id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
 
-    for multi_buy_product in multi_buy_products {
-        products_with_ids.push(MultiBuyProductProductIdPair {
-            multi_buy_product,
-            product_id: get_product_id_by_id_or_alias(multi_buy_product, transaction).await?,
-        });
+        let result = execute_repeat_query("test_user", true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::ProductUnavailable { product_name }) if product_name == "Enabled"
+        ));
     }
 
-    Ok(products_with_ids)
-}
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn repeat_split_order(pool: PgPool) {
+        let split_with = ["trusted_user".to_string()];
+        execute_multi_buy_query(
+            "test_user",
+            &split_with,
+            &multi_buy_products("test_user enabled"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
 
-async fn get_product_id_by_id_or_alias(
-    product: &MultiBuyProduct,
-    transaction: &mut Transaction<'static, Postgres>,
-) -> Result<ProductId, MultiBuyExecutorError> {
-    let product_id_or_error = product.product_name.parse::<ProductId>();
+        let (_, MultiBuyResult { shares, .. }) = execute_repeat_query("test_user", true, &pool)
+            .await
+            .unwrap();
 
-    match product_id_or_error {
-        Ok(product_id) => Ok(product_id),
-        Err(_) => Ok(get_product_id_by_alias(&product.product_name, transaction).await?),
+        let shares = shares
+            .iter()
+            .map(|s| {
+                (
+                    s.username.as_str(),
+                    s.share.as_str(),
+                    s.new_user_balance.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            shares,
+            [
+                ("test_user", "3.50", "93.00"),
+                ("trusted_user", "3.50", "-7.00")
+            ]
+        );
     }
-}
 
-async fn get_product_id_by_alias(
-    product_name: &str,
-    transaction: &mut Transaction<'static, Postgres>,
-) -> Result<ProductId, MultiBuyExecutorError> {
-    sqlx::query_scalar!(
-        r#"
-        SELECT product_id as "id: ProductId"
-        FROM product_aliases
-        WHERE alias_name = LOWER($1) -- alias_name is always lower due to constraint
-        "#,
-        product_name
-    )
-    .fetch_optional(&mut **transaction)
-    .await?
-    .ok_or_else(|| MultiBuyExecutorError::InvalidProduct(product_name.to_string()))
-}
+    #[sqlx::test(fixtures("../../fixtures/users.sql"))]
+    async fn repeat_without_purchases(pool: PgPool) {
+        let result = execute_repeat_query("test_user", true, &pool).await;
 
-async fn purchase_products(
-    user_id: UserId,
-    multi_buy_products_with_ids: &[MultiBuyProductProductIdPair<'_>],
-    transaction: &mut Transaction<'static, Postgres>,
-) -> Result<HashMap<ProductId, i32>, sqlx::Error> {
-    let mut product_bought = HashMap::new();
-    for multi_buy_product_with_id in multi_buy_products_with_ids {
-        for _ in 0..(multi_buy_product_with_id.multi_buy_product.amount.into()) {
-            purchase_product(user_id, multi_buy_product_with_id.product_id, transaction).await?;
-            *product_bought
-                .entry(multi_buy_product_with_id.product_id)
-                .or_insert(0) += 1;
-        }
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::NoPurchaseToRepeat(username)) if username == "test_user"
+        ));
     }
 
-    Ok(product_bought)
-}
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn preview_repeat_does_not_buy(pool: PgPool) {
+        let MultiBuyResult { order_id, .. } = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user enabled:3"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
 
-async fn purchase_product(
-    user_id: UserId,
-    product_id: ProductId,
-    transaction: &mut Transaction<'static, Postgres>,
-) -> Result<(), sqlx::Error> {
-    let rows_affected = sqlx::query!(
-        r#"
-        INSERT INTO sales(price, product_id, user_id)
-        VALUES ((SELECT price FROM products WHERE id = $1), $1, $2)
-        "#,
-        product_id as ProductId,
-        user_id as UserId
-    )
-    .execute(&mut **transaction)
-    .await?
-    .rows_affected();
+        let (
+            repeated_order_id,
+            MultiBuyPreview {
+                products: previewed_products,
+                product_price_sum,
+                new_user_balance,
+                ..
+            },
+        ) = preview_repeat_query("test_user", true, &pool)
+            .await
+            .unwrap();
 
-    assert_eq!(rows_affected, 1);
+        assert_eq!(repeated_order_id, order_id);
+        assert_eq!(previewed_products.len(), 1);
+        assert_eq!(previewed_products[0].amount.get(), 3);
+        assert_eq!(product_price_sum.to_string(), "21.00");
+        assert_eq!(new_user_balance.to_string(), "58.00");
 
-    Ok(())
-}
+        let sales_count = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM sales"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sales_count, 3);
+    }
 
-#[serde_as]
-#[derive(Error, Debug, Serialize)]
-#[serde(tag = "type", content = "context")]
-pub enum MultiBuyExecutorError {
-    #[error("database error: {0}")]
-    DbError(
-        #[serde_as(as = "DisplayFromStr")]
-        #[from]
-        sqlx::Error,
-    ),
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_gift_records_payer_and_consumer(pool: PgPool) {
+        let products = multi_buy_products("test_user enabled @trusted_user:enabled:2");
 
-    #[error("invalid username: {0}")]
-    InvalidUsername(String),
+        let MultiBuyResult {
+            bought_products,
+            product_price_sum,
+            new_user_balance,
+            ..
+        } = execute_multi_buy_query("test_user", &[], &products, true, &pool)
+            .await
+            .unwrap();
 
-    #[error("invalid product: {0}")]
-    InvalidProduct(String),
+        assert_eq!(product_price_sum.to_string(), "21.00");
+        assert_eq!(new_user_balance.to_string(), "79.00");
+        assert_eq!(bought_products.len(), 2);
+        assert_eq!(bought_products[0].consumer, None);
+        assert_eq!(bought_products[1].consumer.as_deref(), Some("trusted_user"));
+        assert_eq!(bought_products[1].amount, 2);
 
-    #[error("user {username} has insufficient funds to pay for: {product_price_sum}")]
-    InsufficientFunds {
-        username: String,
-        product_price_sum: StregCents,
-    },
+        let sales = sqlx::query!(
+            r#"SELECT user_id, consumer_id, COUNT(*) as "count!" FROM sales GROUP BY user_id, consumer_id ORDER BY consumer_id"#
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let sales = sales
+            .iter()
+            .map(|s| (s.user_id, s.consumer_id, s.count))
+            .collect::<Vec<_>>();
+        assert_eq!(sales, [(1, 1, 1), (1, 2, 2)]);
 
-    #[error("stregcents overflow / underflow")]
-    StregCentsOverflow,
-}
+        let trusted_user_balance = sqlx::query_scalar!(
+            r#"SELECT balance as "balance: StregCents" FROM users WHERE id = 2"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(trusted_user_balance.to_string(), "0.00");
+    }
 
-struct MultiBuyProductProductIdPair<'a> {
-    multi_buy_product: &'a MultiBuyProduct,
-    product_id: ProductId,
-}
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_gift_unknown_consumer(pool: PgPool) {
+        let products = multi_buy_products("test_user @i_do_not_exist:enabled");
 
-#[cfg(test)]
-mod tests {
-    use std::num::NonZeroU32;
+        let result = execute_multi_buy_query("test_user", &[], &products, true, &pool).await;
 
-    use super::*;
+        assert!(
+            matches!(result, Err(MultiBuyExecutorError::InvalidUsername { username, .. }) if username == "i_do_not_exist")
+        );
+    }
 
     #[sqlx::test(fixtures(
         "../../fixtures/users.sql",
         "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/product_quotas.sql",
         "../../fixtures/deposits.sql"
     ))]
-    async fn multi_buy_buy_product_by_id(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "1".to_string(),
-            amount: NonZeroU32::new(1).unwrap(),
-        };
-
-        execute_multi_buy_query("test_user", &[product], &pool)
+    async fn multi_buy_gift_counts_towards_consumer_quota(pool: PgPool) {
+        let products = multi_buy_products("test_user @trusted_user:rationed:2 rationed:2");
+        execute_multi_buy_query("test_user", &[], &products, true, &pool)
             .await
             .unwrap();
+
+        let products = multi_buy_products("test_user @trusted_user:rationed");
+        let result = execute_multi_buy_query("test_user", &[], &products, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::QuotaExceeded { username, remaining: 0, .. }) if username == "trusted_user"
+        ));
     }
 
     #[sqlx::test(fixtures(
@@ -298,15 +3210,15 @@ mod tests {
         "../../fixtures/product_aliases.sql",
         "../../fixtures/deposits.sql"
     ))]
-    async fn multi_buy_buy_product_by_alias(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "enabled".to_string(),
-            amount: NonZeroU32::new(1).unwrap(),
-        };
+    async fn multi_buy_gift_to_underage_consumer(pool: PgPool) {
+        let products = multi_buy_products("test_user @minor_user:restricted");
 
-        execute_multi_buy_query("test_user", &[product], &pool)
-            .await
-            .unwrap();
+        let result = execute_multi_buy_query("test_user", &[], &products, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::AgeRestricted { username, .. }) if username == "minor_user"
+        ));
     }
 
     #[sqlx::test(fixtures(
@@ -315,15 +3227,15 @@ mod tests {
         "../../fixtures/product_aliases.sql",
         "../../fixtures/deposits.sql"
     ))]
-    async fn multi_buy_buy_product_by_alias_case_insensitive(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "eNaBlEd".to_string(),
-            amount: NonZeroU32::new(1).unwrap(),
-        };
+    async fn multi_buy_gift_stock_covers_all_consumers(pool: PgPool) {
+        let products = multi_buy_products("test_user limited @trusted_user:limited:2");
 
-        execute_multi_buy_query("test_user", &[product], &pool)
-            .await
-            .unwrap();
+        let result = execute_multi_buy_query("test_user", &[], &products, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::OutOfStock { available: 2, .. })
+        ));
     }
 
     #[sqlx::test(fixtures(
@@ -332,88 +3244,108 @@ mod tests {
         "../../fixtures/product_aliases.sql",
         "../../fixtures/deposits.sql"
     ))]
-    async fn multi_buy_case_insensitive_username(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "enabled".to_string(),
-            amount: NonZeroU32::new(1).unwrap(),
-        };
+    async fn multi_buy_split_between_users(pool: PgPool) {
+        let products = multi_buy_products("test_user enabled");
+        let split_with = ["trusted_user".to_string(), "minor_user".to_string()];
 
-        execute_multi_buy_query("TeSt_UsEr", &[product], &pool)
+        let MultiBuyResult {
+            product_price_sum,
+            new_user_balance,
+            shares,
+            ..
+        } = execute_multi_buy_query("test_user", &split_with, &products, true, &pool)
             .await
             .unwrap();
-    }
 
-    #[sqlx::test]
-    async fn multi_buy_invalid_username(pool: PgPool) {
-        let result = execute_multi_buy_query("i_do_not_exist", &[], &pool).await;
-
-        assert!(
-            matches!(result, Err(MultiBuyExecutorError::InvalidUsername(username)) if username == "i_do_not_exist")
+        assert_eq!(product_price_sum.to_string(), "7.00");
+        assert_eq!(new_user_balance.to_string(), "97.66");
+        let shares = shares
+            .iter()
+            .map(|s| {
+                (
+                    s.username.as_str(),
+                    s.share.as_str(),
+                    s.new_user_balance.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            shares,
+            [
+                ("test_user", "2.34", "97.66"),
+                ("trusted_user", "2.33", "-2.33"),
+                ("minor_user", "2.33", "-2.33")
+            ]
         );
-    }
-
-    #[sqlx::test(fixtures("../../fixtures/users.sql"))]
-    async fn multi_buy_invalid_product_unknown(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "1337".to_string(),
-            amount: NonZeroU32::new(1).unwrap(),
-        };
-        let result = execute_multi_buy_query("test_user", &[product], &pool).await;
 
-        assert!(
-            matches!(result, Err(MultiBuyExecutorError::InvalidProduct(product_name)) if product_name == "1337")
-        );
+        let balances = sqlx::query_scalar!(
+            r#"SELECT balance as "balance: StregCents" FROM users ORDER BY id"#
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        let balances = balances.iter().map(|b| b.to_string()).collect::<Vec<_>>();
+        assert_eq!(balances, ["97.66", "-2.33", "-2.33"]);
     }
 
     #[sqlx::test(fixtures(
         "../../fixtures/users.sql",
         "../../fixtures/products.sql",
-        "../../fixtures/product_aliases.sql"
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
     ))]
-    async fn multi_buy_invalid_product_inactive(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "inactive".to_string(),
-            amount: NonZeroU32::new(1).unwrap(),
-        };
-        let result = execute_multi_buy_query("test_user", &[product], &pool).await;
+    async fn multi_buy_split_insufficient_funds(pool: PgPool) {
+        let products = multi_buy_products("test_user expensive");
+        let split_with = ["trusted_user".to_string()];
 
-        assert!(
-            matches!(result, Err(MultiBuyExecutorError::InvalidProduct(product_name)) if product_name == "inactive")
-        );
+        let result =
+            execute_multi_buy_query("test_user", &split_with, &products, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::InsufficientFunds { username, .. }) if username == "test_user"
+        ));
+        let sales = sqlx::query_scalar!(r#"SELECT COUNT(*) as "count!" FROM sales"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(sales, 0);
     }
 
     #[sqlx::test(fixtures(
         "../../fixtures/users.sql",
         "../../fixtures/products.sql",
-        "../../fixtures/product_aliases.sql"
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
     ))]
-    async fn multi_buy_invalid_product_deactivated_by_timestamp(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "inactive_timestamp".to_string(),
-            amount: NonZeroU32::new(1).unwrap(),
-        };
-        let result = execute_multi_buy_query("test_user", &[product], &pool).await;
+    async fn multi_buy_split_with_unknown_user(pool: PgPool) {
+        let products = multi_buy_products("test_user enabled");
+        let split_with = ["i_do_not_exist".to_string()];
+
+        let result =
+            execute_multi_buy_query("test_user", &split_with, &products, true, &pool).await;
 
         assert!(
-            matches!(result, Err(MultiBuyExecutorError::InvalidProduct(product_name)) if product_name == "inactive_timestamp")
+            matches!(result, Err(MultiBuyExecutorError::InvalidUsername { username, .. }) if username == "i_do_not_exist")
         );
     }
 
     #[sqlx::test(fixtures(
         "../../fixtures/users.sql",
         "../../fixtures/products.sql",
-        "../../fixtures/product_aliases.sql"
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
     ))]
-    async fn multi_buy_insufficient_funds_no_money(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "enabled".to_string(),
-            amount: NonZeroU32::new(1).unwrap(),
-        };
-        let result = execute_multi_buy_query("test_user", &[product], &pool).await;
+    async fn multi_buy_split_with_underage_user(pool: PgPool) {
+        let products = multi_buy_products("test_user restricted");
+        let split_with = ["minor_user".to_string()];
+
+        let result =
+            execute_multi_buy_query("test_user", &split_with, &products, true, &pool).await;
 
         assert!(matches!(
             result,
-            Err(MultiBuyExecutorError::InsufficientFunds { .. })
+            Err(MultiBuyExecutorError::AgeRestricted { username, .. }) if username == "minor_user"
         ));
     }
 
@@ -423,34 +3355,66 @@ mod tests {
         "../../fixtures/product_aliases.sql",
         "../../fixtures/deposits.sql"
     ))]
-    async fn multi_buy_insufficient_funds_too_expensive(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "expensive".to_string(),
-            amount: NonZeroU32::new(1).unwrap(),
-        };
-        let result = execute_multi_buy_query("test_user", &[product], &pool).await;
+    async fn undo_split_multi_buy(pool: PgPool) {
+        let products = multi_buy_products("test_user enabled");
+        let split_with = ["trusted_user".to_string()];
+        execute_multi_buy_query("test_user", &split_with, &products, true, &pool)
+            .await
+            .unwrap();
 
-        assert!(matches!(
-            result,
-            Err(MultiBuyExecutorError::InsufficientFunds { .. })
-        ));
+        let (_, refunded_price_sum, new_user_balance) =
+            execute_undo_query("test_user", Duration::from_secs(60), true, &pool)
+                .await
+                .unwrap();
+
+        assert_eq!(refunded_price_sum.to_string(), "7.00");
+        assert_eq!(new_user_balance.to_string(), "100.00");
+        let trusted_user_balance = sqlx::query_scalar!(
+            r#"SELECT balance as "balance: StregCents" FROM users WHERE id = 2"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(trusted_user_balance.to_string(), "0.00");
     }
 
     #[sqlx::test(fixtures(
         "../../fixtures/users.sql",
         "../../fixtures/products.sql",
-        "../../fixtures/product_aliases.sql"
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
     ))]
-    async fn multi_buy_streg_cents_overflow(pool: PgPool) {
-        let product = MultiBuyProduct {
-            product_name: "overflow".to_string(),
-            amount: NonZeroU32::MAX,
-        };
-        let result = execute_multi_buy_query("test_user", &[product], &pool).await;
+    async fn undo_split_multi_buy_locks_sharers_in_id_order(pool: PgPool) {
+        // The sharer test_user has a lower id than the buyer trusted_user
+        let products = multi_buy_products("trusted_user enabled");
+        let split_with = ["test_user".to_string()];
+        execute_multi_buy_query("trusted_user", &split_with, &products, true, &pool)
+            .await
+            .unwrap();
 
-        assert!(matches!(
-            result,
-            Err(MultiBuyExecutorError::StregCentsOverflow)
-        ));
+        // A purchase in progress has locked test_user and is about to lock trusted_user
+        let test_user_id = get_user_id_by_name("test_user", &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut purchase_transaction = pool.begin().await.unwrap();
+        lock_users_by_ids(&[test_user_id], &mut purchase_transaction)
+            .await
+            .unwrap();
+
+        let undo_pool = pool.clone();
+        let undo = tokio::spawn(async move {
+            execute_undo_query("trusted_user", Duration::from_secs(60), true, &undo_pool).await
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // The undo waits for test_user without holding trusted_user, so the purchase can go on
+        sqlx::query!("SELECT id FROM users WHERE username = 'trusted_user' FOR UPDATE NOWAIT")
+            .fetch_one(&mut *purchase_transaction)
+            .await
+            .expect("the undo should not hold the lock on trusted_user");
+        purchase_transaction.rollback().await.unwrap();
+
+        assert!(undo.await.unwrap().is_ok());
     }
 }
@@ -0,0 +1,438 @@
+use std::collections::{HashMap, HashSet};
+
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+
+use crate::dso::user::UserId;
+use crate::protocol::macros::{MacroError, QuickBuyMacro};
+
+use super::executor::{get_user_id_by_name, get_username_suggestions, MultiBuyExecutorError};
+use super::parser::{is_valid_macro_name, parse_macro_expansion, MultiBuyProduct};
+
+pub async fn get_user_macros(
+    username: &str,
+    suggest_usernames: bool,
+    pool: &PgPool,
+) -> Result<Vec<QuickBuyMacro>, MacroError> {
+    let user_id = get_macro_user_id(username, suggest_usernames, pool).await?;
+
+    Ok(get_macros_by_user_id(user_id, pool).await?)
+}
+
+pub async fn set_user_macro(
+    username: &str,
+    name: &str,
+    expansion: &str,
+    suggest_usernames: bool,
+    pool: &PgPool,
+) -> Result<QuickBuyMacro, MacroError> {
+    let user_id = get_macro_user_id(username, suggest_usernames, pool).await?;
+
+    let name = name.to_lowercase();
+    if !is_valid_macro_name(&name) {
+        return Err(MacroError::InvalidName(name));
+    }
+    // A macro named like an alias would silently replace the product for this user
+    if is_alias(&name, pool).await? {
+        return Err(MacroError::NameIsAlias(name));
+    }
+    parse_macro_expansion(expansion).map_err(MacroError::InvalidExpansion)?;
+
+    let quickbuy_macro = sqlx::query_as!(
+        QuickBuyMacro,
+        r#"
+        INSERT INTO quickbuy_macros(user_id, name, expansion)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (user_id, name) DO UPDATE SET expansion = EXCLUDED.expansion
+        RETURNING name, expansion
+        "#,
+        user_id as UserId,
+        name,
+        expansion.trim()
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(quickbuy_macro)
+}
+
+pub async fn delete_user_macro(
+    username: &str,
+    name: &str,
+    suggest_usernames: bool,
+    pool: &PgPool,
+) -> Result<QuickBuyMacro, MacroError> {
+    let user_id = get_macro_user_id(username, suggest_usernames, pool).await?;
+
+    sqlx::query_as!(
+        QuickBuyMacro,
+        r#"
+        DELETE FROM quickbuy_macros
+        WHERE user_id = $1 AND name = LOWER($2)
+        RETURNING name, expansion
+        "#,
+        user_id as UserId,
+        name
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| MacroError::UnknownMacro(name.to_string()))
+}
+
+// Whether the name would be resolved to a product, exactly or through its normalized form
+async fn is_alias(name: &str, pool: &PgPool) -> Result<bool, sqlx::Error> {
+    Ok(!get_existing_aliases(&[name.to_string()], pool)
+        .await?
+        .is_empty())
+}
+
+async fn get_macro_user_id(
+    username: &str,
+    suggest_usernames: bool,
+    pool: &PgPool,
+) -> Result<UserId, MacroError> {
+    if let Some(user_id) = get_user_id_by_name(username, pool).await? {
+        return Ok(user_id);
+    }
+
+    // Suggesting usernames reveals who has an account, so it is opt-in
+    let suggestions = if suggest_usernames {
+        get_username_suggestions(username, pool).await?
+    } else {
+        vec![]
+    };
+    Err(MacroError::InvalidUsername {
+        username: username.to_string(),
+        suggestions,
+    })
+}
+
+async fn get_macros_by_user_id<'a, E>(
+    user_id: UserId,
+    executor: E,
+) -> Result<Vec<QuickBuyMacro>, sqlx::Error>
+where
+    E: PgExecutor<'a>,
+{
+    sqlx::query_as!(
+        QuickBuyMacro,
+        r#"
+        SELECT name, expansion
+        FROM quickbuy_macros
+        WHERE user_id = $1
+        ORDER BY name
+        "#,
+        user_id as UserId
+    )
+    .fetch_all(executor)
+    .await
+}
+
+// The names that resolve to an alias of an active product, matched the same way products are resolved
+async fn get_existing_aliases<'a, E>(
+    product_names: &[String],
+    executor: E,
+) -> Result<HashSet<String>, sqlx::Error>
+where
+    E: PgExecutor<'a>,
+{
+    let aliases = sqlx::query_scalar!(
+        r#"
+        SELECT product_names.product_name as "product_name!"
+        FROM UNNEST($1::text[]) AS product_names(product_name)
+        WHERE EXISTS(
+            SELECT 1
+            FROM product_aliases
+            JOIN products
+            ON products.id = product_aliases.product_id
+            WHERE product_aliases.alias_normalized IN (normalize_alias(product_names.product_name), normalize_alias(REPLACE(LOWER(product_names.product_name), 'oe', 'ø')))
+              AND is_active_now(products.active, products.activate_after_timestamp, products.deactivate_after_timestamp)
+        )
+        "#,
+        product_names
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(aliases.into_iter().collect())
+}
+
+// Replaces the user's macros with the products they stand for, so products are resolved as if they
+// had been typed in. Amounts are multiplied ("morgen:2") and gifting a macro gifts every product in it.
+// Expanded products keep the span of the macro in the query. Macros are not expanded recursively.
+// Aliases take precedence, so a macro can't shadow an alias added after the macro was.
+pub async fn expand_macros(
+    username: &str,
+    multi_buy_products: &[MultiBuyProduct],
+    transaction: &mut Transaction<'static, Postgres>,
+) -> Result<Vec<MultiBuyProduct>, MultiBuyExecutorError> {
+    // Unknown users are reported when the purchase is prepared
+    let Some(user_id) = get_user_id_by_name(username, &mut **transaction).await? else {
+        return Ok(multi_buy_products.to_vec());
+    };
+    let macros = get_macros_by_user_id(user_id, &mut **transaction)
+        .await?
+        .into_iter()
+        .map(|m| (m.name, m.expansion))
+        .collect::<HashMap<String, String>>();
+    let product_names = multi_buy_products
+        .iter()
+        .map(|p| p.product_name.to_lowercase())
+        .collect::<Vec<String>>();
+    let aliases = get_existing_aliases(&product_names, &mut **transaction).await?;
+
+    let mut expanded_products = vec![];
+    for multi_buy_product in multi_buy_products {
+        let product_name = multi_buy_product.product_name.to_lowercase();
+        let expansion = match macros.get(&product_name) {
+            Some(expansion) if !aliases.contains(&product_name) => expansion,
+            _ => {
+                expanded_products.push(multi_buy_product.clone());
+                continue;
+            }
+        };
+
+        let macro_products =
+            parse_macro_expansion(expansion).map_err(|_| MultiBuyExecutorError::InvalidMacro {
+                macro_name: multi_buy_product.product_name.clone(),
+            })?;
+        for macro_product in macro_products {
+            expanded_products.push(MultiBuyProduct {
+                product_name: macro_product.product_name,
+                amount: macro_product
+                    .amount
+                    .checked_mul(multi_buy_product.amount)
+                    .ok_or(MultiBuyExecutorError::AmountOverflow)?,
+                consumer: macro_product
+                    .consumer
+                    .or_else(|| multi_buy_product.consumer.clone()),
+                span: multi_buy_product.span,
+            });
+        }
+    }
+
+    Ok(expanded_products)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quickbuy::executor::{execute_multi_buy_query, MultiBuyResult};
+    use crate::quickbuy::parser::multi_buy_products;
+
+    #[sqlx::test(fixtures("../../fixtures/users.sql"))]
+    async fn set_get_and_delete_macro(pool: PgPool) {
+        let quickbuy_macro =
+            set_user_macro("test_user", "Morgen", " kaffe croissant ", true, &pool)
+                .await
+                .unwrap();
+        assert_eq!(quickbuy_macro.name, "morgen");
+        assert_eq!(quickbuy_macro.expansion, "kaffe croissant");
+
+        set_user_macro("test_user", "morgen", "kaffe:2", true, &pool)
+            .await
+            .unwrap();
+        let macros = get_user_macros("test_user", true, &pool).await.unwrap();
+        assert_eq!(macros.len(), 1);
+        assert_eq!(macros[0].expansion, "kaffe:2");
+        assert!(get_user_macros("trusted_user", true, &pool)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let deleted_macro = delete_user_macro("test_user", "MORGEN", true, &pool)
+            .await
+            .unwrap();
+        assert_eq!(deleted_macro.name, "morgen");
+        assert!(matches!(
+            delete_user_macro("test_user", "morgen", true, &pool).await,
+            Err(MacroError::UnknownMacro(_))
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql"
+    ))]
+    async fn set_macro_named_like_alias(pool: PgPool) {
+        for name in ["øl", "Øl", "ol", "oel"] {
+            assert!(
+                matches!(
+                    set_user_macro("test_user", name, "enabled", true, &pool).await,
+                    Err(MacroError::NameIsAlias(_))
+                ),
+                "{name} should be rejected"
+            );
+        }
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_alias_takes_precedence_over_macro(pool: PgPool) {
+        set_user_macro("test_user", "kaffe", "enabled", true, &pool)
+            .await
+            .unwrap();
+        // The alias is added after the macro, so the macro could not be rejected
+        sqlx::query!("INSERT INTO product_aliases(alias_name, product_id) VALUES ('kaffe', 12)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let MultiBuyResult {
+            bought_products, ..
+        } = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user kaffe"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(bought_products[0].product_name, "Café");
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql"
+    ))]
+    async fn multi_buy_normalized_alias_takes_precedence_over_macro(pool: PgPool) {
+        sqlx::query!("DELETE FROM product_aliases WHERE product_id = 11")
+            .execute(&pool)
+            .await
+            .unwrap();
+        set_user_macro("test_user", "ol", "enabled", true, &pool)
+            .await
+            .unwrap();
+        // ol is how øl is typed without the ø, so the new alias is what ol means from now on
+        sqlx::query!("INSERT INTO product_aliases(alias_name, product_id) VALUES ('øl', 11)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let MultiBuyResult {
+            bought_products, ..
+        } = execute_multi_buy_query(
+            "test_user",
+            &[],
+            &multi_buy_products("test_user ol"),
+            true,
+            &pool,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(bought_products[0].product_name, "Øl");
+    }
+
+    #[sqlx::test(fixtures("../../fixtures/users.sql"))]
+    async fn set_invalid_macro(pool: PgPool) {
+        assert!(matches!(
+            set_user_macro("i_do_not_exist", "morgen", "kaffe", true, &pool).await,
+            Err(MacroError::InvalidUsername { username, .. }) if username == "i_do_not_exist"
+        ));
+        assert!(matches!(
+            set_user_macro("test_user", "21", "kaffe", true, &pool).await,
+            Err(MacroError::InvalidName(_))
+        ));
+        assert!(matches!(
+            set_user_macro("test_user", "morgen", "kaffe:0", true, &pool).await,
+            Err(MacroError::InvalidExpansion(_))
+        ));
+        assert!(matches!(
+            set_user_macro("test_user", "morgen", " ", true, &pool).await,
+            Err(MacroError::InvalidExpansion(_))
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql",
+        "../../fixtures/quickbuy_macros.sql"
+    ))]
+    async fn multi_buy_expands_macro(pool: PgPool) {
+        let products = multi_buy_products("test_user MORGEN:2 enabled");
+
+        let MultiBuyResult {
+            bought_products,
+            product_price_sum,
+            ..
+        } = execute_multi_buy_query("test_user", &[], &products, true, &pool)
+            .await
+            .unwrap();
+
+        let bought_products = bought_products
+            .iter()
+            .map(|p| (p.product_name.as_str(), p.amount))
+            .collect::<Vec<_>>();
+        assert_eq!(bought_products, [("Enabled", 3), ("Rationed", 4)]);
+        assert_eq!(product_price_sum.to_string(), "25.00");
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql",
+        "../../fixtures/quickbuy_macros.sql"
+    ))]
+    async fn multi_buy_gifts_macro(pool: PgPool) {
+        let products = multi_buy_products("test_user @trusted_user:morgen");
+
+        let MultiBuyResult {
+            bought_products, ..
+        } = execute_multi_buy_query("test_user", &[], &products, true, &pool)
+            .await
+            .unwrap();
+
+        assert!(bought_products
+            .iter()
+            .all(|p| p.consumer.as_deref() == Some("trusted_user")));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql",
+        "../../fixtures/quickbuy_macros.sql"
+    ))]
+    async fn multi_buy_macro_with_invalid_product(pool: PgPool) {
+        let products = multi_buy_products("test_user enabled broken");
+
+        let result = execute_multi_buy_query("test_user", &[], &products, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::InvalidProduct { product_name, span, .. })
+                if product_name == "nope" && span.byte_start == 18
+        ));
+    }
+
+    #[sqlx::test(fixtures(
+        "../../fixtures/users.sql",
+        "../../fixtures/products.sql",
+        "../../fixtures/product_aliases.sql",
+        "../../fixtures/deposits.sql",
+        "../../fixtures/quickbuy_macros.sql"
+    ))]
+    async fn multi_buy_ignores_other_users_macros(pool: PgPool) {
+        let products = multi_buy_products("test_user aften");
+
+        let result = execute_multi_buy_query("test_user", &[], &products, true, &pool).await;
+
+        assert!(matches!(
+            result,
+            Err(MultiBuyExecutorError::InvalidProduct { product_name, .. }) if product_name == "aften"
+        ));
+    }
+}
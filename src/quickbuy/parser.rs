@@ -1,63 +1,257 @@
-use std::num::{NonZeroU32, ParseIntError};
+use std::{
+    fmt::Display,
+    num::{NonZeroU32, ParseIntError},
+};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use thiserror::Error;
 
-pub fn parse_quickbuy_query(quickbuy_query: &str) -> Result<QuickBuyType, QuickBuyParseError> {
-    let trimmed = quickbuy_query.trim();
+// Reserved token that undoes the user's most recent purchase, e.g. "alice !undo"
+pub const UNDO_TOKEN: &str = "!undo";
+
+// Reserved token that buys the products of the user's most recent order again, e.g. "alice !!"
+pub const REPEAT_TOKEN: &str = "!!";
 
-    let split = trimmed
-        .split(' ')
-        .filter(|&s| !s.is_empty())
-        .collect::<Vec<&str>>();
-    match split.len() {
-        0 => Err(QuickBuyParseError::EmptyQuery),
+// Marks a product bought for another user, e.g. "alice @bob:øl:2"
+const GIFT_PREFIX: char = '@';
+
+// Separates the users splitting the cost of a purchase, e.g. "alice,bob,carol øl:3"
+const SPLIT_SEPARATOR: char = ',';
+
+pub fn parse_quickbuy_query(quickbuy_query: &str) -> Result<QuickBuyType, QuickBuyParseError> {
+    let tokens = tokenize(quickbuy_query);
+    match tokens.len() {
+        0 => Err(QuickBuyParseError::EmptyQuery {
+            span: Span::new(quickbuy_query, 0, quickbuy_query.len()),
+        }),
         1 => Ok(QuickBuyType::Username {
-            username: split[0].into(),
+            username: tokens[0].text.into(),
+        }),
+        2 if tokens[1].text == UNDO_TOKEN => Ok(QuickBuyType::Undo {
+            username: tokens[0].text.into(),
+        }),
+        2 if tokens[1].text == REPEAT_TOKEN => Ok(QuickBuyType::Repeat {
+            username: tokens[0].text.into(),
         }),
-        _ => Ok(parse_multi_buy_expression(&split)?),
+        _ => Ok(parse_multi_buy_expression(&tokens)?),
     }
 }
 
-fn parse_multi_buy_expression(split: &[&str]) -> Result<QuickBuyType, QuickBuyParseError> {
-    let username = split[0];
-    let rest = &split[1..];
+// Splits the trimmed query on spaces, remembering where in the original query each token is
+fn tokenize(quickbuy_query: &str) -> Vec<Token<'_>> {
+    let trimmed = quickbuy_query.trim();
+    let mut byte_start = quickbuy_query.len() - quickbuy_query.trim_start().len();
+
+    let mut tokens = vec![];
+    for text in trimmed.split(' ') {
+        if !text.is_empty() {
+            tokens.push(Token {
+                text,
+                span: Span::new(quickbuy_query, byte_start, byte_start + text.len()),
+            });
+        }
+        byte_start += text.len() + ' '.len_utf8();
+    }
 
-    let products = rest
-        .iter()
-        .map(|product| parse_multi_buy_product(product))
-        .collect::<Result<Vec<MultiBuyProduct>, QuickBuyParseError>>()?;
+    tokens
+}
+
+// Grammar of a multibuy, tokens are separated by one or more spaces:
+//
+//   multibuy  = usernames { product }
+//   usernames = username { "," username }   (the cost is split evenly, e.g. "alice,bob")
+//   product   = count name                  (only when name is bare and not a number, e.g. "3 øl")
+//             | "@" username ":" item       (bought for another user, e.g. "@bob:øl:2")
+//             | item
+//   item      = count "x" name              (e.g. "2xkaffe")
+//             | name ":" count              (e.g. "øl:2")
+//             | name "*" count              (e.g. "kaffe*2")
+//             | name                        (e.g. "kaffe" or the product id "21")
+//   count     = digit { digit }
+//   name      = any characters except space, ":" and "*", not starting with "@"
+//   username  = any characters except space, ":" and ","
+//
+// A bare name is a name that is not written in any of the forms with a count.
+// A number followed by a number stays two product ids, so "alice 3 21" buys products 3 and 21.
+// A number followed by a bare name is always a count, so "alice 2 øl" buys two øl and not product 2 and an øl
+// as it did before counts could be written in front of names.
+// Aliases are constrained in the database so every alias can be typed as a bare name.
+fn parse_multi_buy_expression(tokens: &[Token]) -> Result<QuickBuyType, QuickBuyParseError> {
+    let (username, split_with) = parse_usernames(&tokens[0])?;
+    let products = parse_products(&tokens[1..])?;
 
     Ok(QuickBuyType::MultiBuy {
         username: username.into(),
+        split_with: split_with.into_iter().map(String::from).collect(),
         products,
     })
 }
 
-fn parse_multi_buy_product(product_query: &str) -> Result<MultiBuyProduct, QuickBuyParseError> {
-    let split = product_query.split(':').collect::<Vec<&str>>();
+// Parses what a quickbuy macro expands to, which is a list of products, e.g. "kaffe croissant"
+pub fn parse_macro_expansion(expansion: &str) -> Result<Vec<MultiBuyProduct>, QuickBuyParseError> {
+    let tokens = tokenize(expansion);
+    if tokens.is_empty() {
+        return Err(QuickBuyParseError::EmptyQuery {
+            span: Span::new(expansion, 0, expansion.len()),
+        });
+    }
 
-    match split.len() {
-        1 => Ok(MultiBuyProduct {
-            product_name: parse_product_name(split[0])?.into(),
-            amount: NonZeroU32::new(1).unwrap(),
-        }),
-        2 => Ok(MultiBuyProduct {
-            product_name: parse_product_name(split[0])?.into(),
-            amount: split[1].parse::<NonZeroU32>()?,
-        }),
-        _ => Err(QuickBuyParseError::Syntax),
+    parse_products(&tokens)
+}
+
+// Macro names are used in place of product names, so they must be bare names
+// that can't be mistaken for product ids or reserved tokens
+pub fn is_valid_macro_name(name: &str) -> bool {
+    is_bare_name(name)
+        && !name.is_empty()
+        && !name.starts_with('!')
+        && !name.contains(|c: char| c.is_whitespace() || c == SPLIT_SEPARATOR)
+}
+
+fn parse_products(tokens: &[Token]) -> Result<Vec<MultiBuyProduct>, QuickBuyParseError> {
+    let mut rest = tokens;
+
+    let mut products = vec![];
+    while let Some((token, tail)) = rest.split_first() {
+        match tail.first() {
+            Some(next) if is_count(token.text) && is_bare_name(next.text) => {
+                let span = token.span.join(next.span);
+                products.push(MultiBuyProduct {
+                    product_name: next.text.into(),
+                    amount: parse_count(token.text, span)?,
+                    consumer: None,
+                    span,
+                });
+                rest = &tail[1..];
+            }
+            _ => {
+                products.push(parse_multi_buy_product(token)?);
+                rest = tail;
+            }
+        }
+    }
+
+    Ok(products)
+}
+
+// The first user makes the purchase, any further users split the cost with them
+fn parse_usernames<'a>(token: &Token<'a>) -> Result<(&'a str, Vec<&'a str>), QuickBuyParseError> {
+    let usernames = token.text.split(SPLIT_SEPARATOR).collect::<Vec<&str>>();
+
+    for (i, username) in usernames.iter().enumerate() {
+        let duplicate = usernames[..i]
+            .iter()
+            .any(|u| u.to_lowercase() == username.to_lowercase());
+        if username.is_empty() || duplicate {
+            return Err(QuickBuyParseError::Syntax { span: token.span });
+        }
+    }
+
+    Ok((usernames[0], usernames[1..].to_vec()))
+}
+
+fn parse_multi_buy_product(token: &Token) -> Result<MultiBuyProduct, QuickBuyParseError> {
+    let span = token.span;
+
+    let (consumer, item) = match token.text.strip_prefix(GIFT_PREFIX) {
+        Some(gift) => match gift.split_once(':') {
+            Some((consumer, item)) if !consumer.is_empty() => (Some(consumer), item),
+            _ => return Err(QuickBuyParseError::Syntax { span }),
+        },
+        None => (None, token.text),
+    };
+
+    // At most one count can be written after the name, and gifts can't be nested
+    if item.matches([':', '*']).count() > 1 || item.starts_with(GIFT_PREFIX) {
+        return Err(QuickBuyParseError::Syntax { span });
     }
+
+    let (product_name, amount) = match item.split_once([':', '*']) {
+        Some((product_name, count)) => (product_name, parse_count(count, span)?),
+        None => match split_count_prefix(item) {
+            Some((count, product_name)) => (product_name, parse_count(count, span)?),
+            None => (item, NonZeroU32::new(1).unwrap()),
+        },
+    };
+
+    Ok(MultiBuyProduct {
+        product_name: parse_product_name(product_name, span)?.into(),
+        amount,
+        consumer: consumer.map(String::from),
+        span,
+    })
+}
+
+fn parse_count(count: &str, span: Span) -> Result<NonZeroU32, QuickBuyParseError> {
+    count
+        .parse::<NonZeroU32>()
+        .map_err(|error| QuickBuyParseError::InvalidAmount { span, error })
+}
+
+// "2xkaffe" is split into ("2", "kaffe")
+fn split_count_prefix(text: &str) -> Option<(&str, &str)> {
+    let count_length = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (count, rest) = text.split_at(count_length);
+    let product_name = rest.strip_prefix('x')?;
+
+    (!count.is_empty()).then_some((count, product_name))
+}
+
+fn is_count(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_digit())
 }
 
-fn parse_product_name(product_name: &str) -> Result<&str, QuickBuyParseError> {
+fn is_bare_name(text: &str) -> bool {
+    !is_count(text)
+        && !text.contains([':', '*'])
+        && !text.starts_with(GIFT_PREFIX)
+        && split_count_prefix(text).is_none()
+}
+
+fn parse_product_name(product_name: &str, span: Span) -> Result<&str, QuickBuyParseError> {
     match product_name.len() {
-        0 => Err(QuickBuyParseError::EmptyProduct),
+        0 => Err(QuickBuyParseError::EmptyProduct { span }),
         _ => Ok(product_name),
     }
 }
 
+struct Token<'a> {
+    text: &'a str,
+    span: Span,
+}
+
+// Where a token is in the quickbuy query, both as byte offsets and as character offsets.
+// End offsets are exclusive.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub byte_start: usize,
+    pub byte_end: usize,
+    pub char_start: usize,
+    pub char_end: usize,
+}
+
+impl Span {
+    fn join(self, other: Span) -> Self {
+        Span {
+            byte_start: self.byte_start,
+            byte_end: other.byte_end,
+            char_start: self.char_start,
+            char_end: other.char_end,
+        }
+    }
+
+    fn new(quickbuy_query: &str, byte_start: usize, byte_end: usize) -> Self {
+        let char_start = quickbuy_query[..byte_start].chars().count();
+        Span {
+            byte_start,
+            byte_end,
+            char_start,
+            char_end: char_start + quickbuy_query[byte_start..byte_end].chars().count(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum QuickBuyType {
     Username {
@@ -65,14 +259,58 @@ pub enum QuickBuyType {
     },
     MultiBuy {
         username: String,
+        // Users splitting the cost evenly with username
+        split_with: Vec<String>,
         products: Vec<MultiBuyProduct>,
     },
+    Undo {
+        username: String,
+    },
+    Repeat {
+        username: String,
+    },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MultiBuyProduct {
     pub product_name: String,
     pub amount: NonZeroU32,
+    // The user the product is bought for, None when the buyer consumes it themselves
+    pub consumer: Option<String>,
+    pub span: Span,
+}
+
+impl MultiBuyProduct {
+    // A product bought by the buyer themselves, as if typed on its own
+    #[cfg(test)]
+    pub fn new(product_name: &str, amount: u32) -> MultiBuyProduct {
+        MultiBuyProduct {
+            product_name: product_name.to_string(),
+            amount: NonZeroU32::new(amount).expect("amounts are positive"),
+            consumer: None,
+            span: Span::default(),
+        }
+    }
+}
+
+// The products of a multi buy query, for tests that buy what a user would type
+#[cfg(test)]
+pub fn multi_buy_products(query: &str) -> Vec<MultiBuyProduct> {
+    let Ok(QuickBuyType::MultiBuy { products, .. }) = parse_quickbuy_query(query) else {
+        panic!("{query} should be a multibuy");
+    };
+
+    products
+}
+
+// Writes the product in the "name:count" form, which parses back to the same product
+impl Display for MultiBuyProduct {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(consumer) = &self.consumer {
+            write!(f, "{GIFT_PREFIX}{consumer}:")?;
+        }
+        write!(f, "{}:{}", self.product_name, self.amount)
+    }
 }
 
 #[serde_as]
@@ -80,20 +318,20 @@ pub struct MultiBuyProduct {
 #[serde(tag = "type", content = "context")]
 pub enum QuickBuyParseError {
     #[error("query is empty")]
-    EmptyQuery,
+    EmptyQuery { span: Span },
 
     #[error("syntax error")]
-    Syntax,
+    Syntax { span: Span },
 
     #[error("empty product name")]
-    EmptyProduct,
+    EmptyProduct { span: Span },
 
-    #[error("invalid amount")]
-    InvalidAmount(
+    #[error("invalid amount: {error}")]
+    InvalidAmount {
+        span: Span,
         #[serde_as(as = "DisplayFromStr")]
-        #[from]
-        ParseIntError,
-    ),
+        error: ParseIntError,
+    },
 }
 
 #[cfg(test)]
@@ -125,8 +363,13 @@ mod tests {
         let result = parse_quickbuy_query("test_user kaffe øl:2 21:3").unwrap();
 
         match result {
-            QuickBuyType::MultiBuy { username, products } => {
+            QuickBuyType::MultiBuy {
+                username,
+                split_with,
+                products,
+            } => {
                 assert_eq!(username, "test_user");
+                assert!(split_with.is_empty());
 
                 let kaffe_product = &products[0];
                 let øl_product = &products[1];
@@ -145,11 +388,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn undo_query() {
+        let result = parse_quickbuy_query("test_user !undo").unwrap();
+
+        assert!(matches!(result, QuickBuyType::Undo { username } if username == "test_user"));
+    }
+
+    #[test]
+    fn repeat_query() {
+        let result = parse_quickbuy_query("test_user !!").unwrap();
+
+        assert!(matches!(result, QuickBuyType::Repeat { username } if username == "test_user"));
+    }
+
     #[test]
     fn empty_product_multibuy_query() {
         let error = parse_quickbuy_query("test_user :2").unwrap_err();
 
-        assert!(matches!(error, QuickBuyParseError::EmptyProduct))
+        assert!(matches!(error, QuickBuyParseError::EmptyProduct { .. }))
     }
 
     #[test]
@@ -172,17 +429,362 @@ mod tests {
         parse_and_expect_invalid_amount_multibuy_query("test_user p:x");
     }
 
+    #[test]
+    fn product_spans() {
+        let result = parse_quickbuy_query(" test_user  øl:2 kaffe").unwrap();
+
+        let QuickBuyType::MultiBuy { products, .. } = result else {
+            unreachable!();
+        };
+        assert_eq!(
+            products[0].span,
+            Span {
+                byte_start: 12,
+                byte_end: 17,
+                char_start: 12,
+                char_end: 16,
+            }
+        );
+        assert_eq!(
+            products[1].span,
+            Span {
+                byte_start: 18,
+                byte_end: 23,
+                char_start: 17,
+                char_end: 22,
+            }
+        );
+    }
+
+    #[test]
+    fn error_span_after_multibyte_characters() {
+        let error = parse_quickbuy_query("test_user øl p:x").unwrap_err();
+
+        let QuickBuyParseError::InvalidAmount { span, .. } = error else {
+            panic!("expected invalid amount");
+        };
+        assert_eq!(
+            span,
+            Span {
+                byte_start: 14,
+                byte_end: 17,
+                char_start: 13,
+                char_end: 16,
+            }
+        );
+    }
+
+    #[test]
+    fn syntax_error_span() {
+        let error = parse_quickbuy_query("test_user a:1:2").unwrap_err();
+
+        assert!(matches!(
+            error,
+            QuickBuyParseError::Syntax { span } if span.byte_start == 10 && span.byte_end == 15
+        ));
+    }
+
+    #[test]
+    fn extended_grammar_query() {
+        let products = parse_products("test_user 2xkaffe kaffe*2 3 øl 2x21 21*3");
+
+        assert_eq!(
+            products,
+            expected(&[("kaffe", 2), ("kaffe", 2), ("øl", 3), ("21", 2), ("21", 3)])
+        );
+    }
+
+    #[test]
+    fn leading_count_only_before_bare_name() {
+        assert_eq!(
+            parse_products("test_user 3 21"),
+            expected(&[("3", 1), ("21", 1)])
+        );
+        assert_eq!(
+            parse_products("test_user 3 øl:2"),
+            expected(&[("3", 1), ("øl", 2)])
+        );
+        assert_eq!(
+            parse_products("test_user 3 2xøl"),
+            expected(&[("3", 1), ("øl", 2)])
+        );
+        assert_eq!(
+            parse_products("test_user kaffe 3"),
+            expected(&[("kaffe", 1), ("3", 1)])
+        );
+        assert_eq!(
+            parse_products("test_user 2 3 øl"),
+            expected(&[("2", 1), ("øl", 3)])
+        );
+    }
+
+    #[test]
+    fn leading_count_before_bare_name_is_not_a_product_id() {
+        // This used to buy product 2 and one øl
+        assert_eq!(parse_products("test_user 2 øl"), expected(&[("øl", 2)]));
+        // The product id can still be bought before a name by giving it a count
+        assert_eq!(
+            parse_products("test_user 2:1 øl"),
+            expected(&[("2", 1), ("øl", 1)])
+        );
+    }
+
+    #[test]
+    fn count_prefix_needs_digits() {
+        assert_eq!(
+            parse_products("test_user xmas 7up x2"),
+            expected(&[("xmas", 1), ("7up", 1), ("x2", 1)])
+        );
+    }
+
+    #[test]
+    fn leading_count_span() {
+        let result = parse_quickbuy_query("test_user 3  øl").unwrap();
+
+        let QuickBuyType::MultiBuy { products, .. } = result else {
+            unreachable!();
+        };
+        assert_eq!(products[0].span.byte_start, 10);
+        assert_eq!(products[0].span.byte_end, 16);
+        assert_eq!(products[0].span.char_end, 15);
+    }
+
+    #[test]
+    fn mixed_count_forms_are_syntax_errors() {
+        for query in [
+            "test_user kaffe*2:3",
+            "test_user kaffe:2*3",
+            "test_user a:1:2",
+        ] {
+            let error = parse_quickbuy_query(query).unwrap_err();
+
+            assert!(
+                matches!(error, QuickBuyParseError::Syntax { .. }),
+                "{query} should be a syntax error"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_extended_amounts() {
+        parse_and_expect_invalid_amount_multibuy_query("test_user 0xkaffe");
+        parse_and_expect_invalid_amount_multibuy_query("test_user kaffe*0");
+        parse_and_expect_invalid_amount_multibuy_query("test_user kaffe*");
+        parse_and_expect_invalid_amount_multibuy_query("test_user 0 kaffe");
+        parse_and_expect_invalid_amount_multibuy_query("test_user 99999999999 kaffe");
+    }
+
+    #[test]
+    fn empty_extended_product_names() {
+        for query in ["test_user *2", "test_user 2x"] {
+            let error = parse_quickbuy_query(query).unwrap_err();
+
+            assert!(
+                matches!(error, QuickBuyParseError::EmptyProduct { .. }),
+                "{query} should have an empty product"
+            );
+        }
+    }
+
+    #[test]
+    fn products_round_trip() {
+        for query in [
+            "test_user kaffe øl:2 21:3",
+            "test_user 2xkaffe kaffe*2 3 øl",
+            "test_user 3 21 2x21 21*4 xmas 7up",
+            "test_user @bob:øl:2 @bob:2xkaffe",
+        ] {
+            let formatted = format_products(query);
+            let reformatted = format_products(&format!("test_user {formatted}"));
+
+            assert_eq!(
+                parse_products(&format!("test_user {formatted}")),
+                parse_products(query),
+                "{query} should round trip through {formatted}"
+            );
+            assert_eq!(formatted, reformatted);
+        }
+    }
+
+    #[test]
+    fn gift_query() {
+        let result =
+            parse_quickbuy_query("test_user kaffe @bob:øl:2 @bob:2xkaffe @carol:21").unwrap();
+
+        let QuickBuyType::MultiBuy { products, .. } = result else {
+            unreachable!();
+        };
+        let products = products
+            .iter()
+            .map(|p| {
+                (
+                    p.product_name.as_str(),
+                    p.amount.get(),
+                    p.consumer.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            products,
+            [
+                ("kaffe", 1, None),
+                ("øl", 2, Some("bob")),
+                ("kaffe", 2, Some("bob")),
+                ("21", 1, Some("carol"))
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_gift_queries() {
+        for query in [
+            "test_user @bob",
+            "test_user @:øl",
+            "test_user @bob:@carol:øl",
+            "test_user @bob:øl:2:3",
+        ] {
+            let error = parse_quickbuy_query(query).unwrap_err();
+
+            assert!(
+                matches!(error, QuickBuyParseError::Syntax { .. }),
+                "{query} should be a syntax error"
+            );
+        }
+
+        let error = parse_quickbuy_query("test_user @bob:").unwrap_err();
+        assert!(matches!(error, QuickBuyParseError::EmptyProduct { .. }));
+    }
+
+    #[test]
+    fn leading_count_before_gift() {
+        assert_eq!(
+            parse_products("test_user 3 @bob:øl"),
+            expected(&[("3", 1), ("øl", 1)])
+        );
+    }
+
+    #[test]
+    fn split_query() {
+        let result = parse_quickbuy_query("alice,bob,carol øl:3").unwrap();
+
+        let QuickBuyType::MultiBuy {
+            username,
+            split_with,
+            products,
+        } = result
+        else {
+            unreachable!();
+        };
+        assert_eq!(username, "alice");
+        assert_eq!(split_with, ["bob", "carol"]);
+        assert_eq!(products[0].product_name, "øl");
+        assert_eq!(products[0].amount.get(), 3);
+    }
+
+    #[test]
+    fn invalid_split_queries() {
+        for query in [
+            "alice,,bob øl",
+            "alice, øl",
+            ",alice øl",
+            "alice,bob,Alice øl",
+        ] {
+            let error = parse_quickbuy_query(query).unwrap_err();
+
+            assert!(
+                matches!(error, QuickBuyParseError::Syntax { span } if span.byte_start == 0),
+                "{query} should be a syntax error"
+            );
+        }
+    }
+
+    #[test]
+    fn macro_expansion() {
+        let products = parse_macro_expansion(" kaffe 2 croissant @bob:øl").unwrap();
+
+        let products = products
+            .iter()
+            .map(|p| {
+                (
+                    p.product_name.as_str(),
+                    p.amount.get(),
+                    p.consumer.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            products,
+            [
+                ("kaffe", 1, None),
+                ("croissant", 2, None),
+                ("øl", 1, Some("bob"))
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_macro_expansion() {
+        let error = parse_macro_expansion("  ").unwrap_err();
+
+        assert!(matches!(error, QuickBuyParseError::EmptyQuery { .. }));
+    }
+
+    #[test]
+    fn macro_names() {
+        for name in ["morgen", "øl_og_chips", "x"] {
+            assert!(is_valid_macro_name(name), "{name} should be a valid name");
+        }
+        for name in [
+            "", "21", "2xkaffe", "kaffe:2", "kaffe*2", "@bob", "!undo", "a,b", "a b",
+        ] {
+            assert!(
+                !is_valid_macro_name(name),
+                "{name} should be an invalid name"
+            );
+        }
+    }
+
+    fn expected(products: &[(&str, u32)]) -> Vec<(String, u32)> {
+        products
+            .iter()
+            .map(|&(product_name, amount)| (product_name.to_string(), amount))
+            .collect()
+    }
+
+    fn format_products(query: &str) -> String {
+        let QuickBuyType::MultiBuy { products, .. } = parse_quickbuy_query(query).unwrap() else {
+            panic!("{query} should be a multibuy");
+        };
+
+        products
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    fn parse_products(query: &str) -> Vec<(String, u32)> {
+        let QuickBuyType::MultiBuy { products, .. } = parse_quickbuy_query(query).unwrap() else {
+            panic!("{query} should be a multibuy");
+        };
+
+        products
+            .into_iter()
+            .map(|p| (p.product_name, p.amount.get()))
+            .collect()
+    }
+
     fn parse_and_expect_empty_query(query: &str) {
         let result = parse_quickbuy_query(query);
         assert!(result.is_err());
         let error = result.unwrap_err();
 
-        assert!(matches!(error, QuickBuyParseError::EmptyQuery));
+        assert!(matches!(error, QuickBuyParseError::EmptyQuery { .. }));
     }
 
     fn parse_and_expect_invalid_amount_multibuy_query(query: &str) {
         let error = parse_quickbuy_query(query).unwrap_err();
 
-        assert!(matches!(error, QuickBuyParseError::InvalidAmount(_)))
+        assert!(matches!(error, QuickBuyParseError::InvalidAmount { .. }))
     }
 }
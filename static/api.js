@@ -13,6 +13,11 @@ export async function getActiveProducts() {
   return await getRequest(url);
 }
 
+export async function getActiveProductCategories() {
+  const url = "/api/products/active/categories";
+  return await getRequest(url);
+}
+
 export async function getUserInfo(username) {
   const url = `/api/users/info?username=${encodeURIComponent(username)}`;
   return await getRequest(url);
@@ -28,6 +33,16 @@ export async function postQuickBuy(quickbuyQuery) {
   return await postRequest(url, { quickbuy: quickbuyQuery });
 }
 
+export async function postQuickBuyPreview(quickbuyQuery) {
+  const url = "/api/purchase/quickbuy/preview";
+  return await postRequest(url, { quickbuy: quickbuyQuery });
+}
+
+export async function postUndo(username) {
+  const url = "/api/purchase/undo";
+  return await postRequest(url, { username: username });
+}
+
 export async function getThemeDefinitions() {
   const url = "/static/themes/definitions.json";
 
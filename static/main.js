@@ -1,19 +1,24 @@
-import { getActiveProducts, postQuickBuy, isResponseOk } from "./api.js";
-import { populateTable, handleQuickBuyError } from "./product-table.js";
+import { getActiveProductCategories, postQuickBuy, postQuickBuyPreview, isResponseOk } from "./api.js";
+import { populateCategories, handleQuickBuyError, getConsumerText, getSharesText, getDiscountsText } from "./product-table.js";
 
 "use strict";
 
+const previewDelayMs = 300;
+
+// Previews can finish out of order, only the one for the latest input may be shown
+let latestPreviewRequest = 0;
+
 document.addEventListener("DOMContentLoaded", initializePage);
 
 async function initializePage() {
   addQuickBuyHandler();
 
   try {
-    const activeProducts = await getActiveProducts();
+    const activeProductCategories = await getActiveProductCategories();
     // TODO: Error handling
-    const products = activeProducts.content.products;
-    window.products = products;
-    populateTable(products, populateProductNameCell);
+    const categories = activeProductCategories.content.categories;
+    window.products = categories.flatMap(c => c.products);
+    populateCategories(categories, populateProductNameCell);
   }
   catch (error) {
     console.error(error.message);
@@ -25,6 +30,36 @@ function addQuickBuyHandler() {
   console.assert(quickBuyForm);
 
   quickBuyForm.addEventListener("submit", performQuickBuy);
+
+  const quickBuyInput = document.getElementById("quickbuy-field");
+  console.assert(quickBuyInput);
+
+  let previewTimeout = null;
+  quickBuyInput.addEventListener("input", () => {
+    clearTimeout(previewTimeout);
+    previewTimeout = setTimeout(() => previewQuickBuy(quickBuyInput.value), previewDelayMs);
+  });
+}
+
+async function previewQuickBuy(quickBuyQuery) {
+  const quickBuyPreviewElement = document.getElementById("quickbuy-preview");
+  console.assert(quickBuyPreviewElement);
+
+  const previewRequest = ++latestPreviewRequest;
+  const response = await postQuickBuyPreview(quickBuyQuery);
+  if (previewRequest !== latestPreviewRequest) {
+    return;
+  }
+
+  // Only multi buys and repeats are previewed; errors are shown when the purchase is submitted
+  if (!isResponseOk(response) || (response.content.type !== "MultiBuy" && response.content.type !== "Repeat")) {
+    quickBuyPreviewElement.innerText = "";
+    return;
+  }
+
+  const productsText = response.content.products.map(p => `${p.amount} stk ${p.product_name} à ${p.unit_price} kr${getConsumerText(p)}`).join(", ");
+  const repeatText = response.content.type === "Repeat" ? "Gentag " : "";
+  quickBuyPreviewElement.innerText = `${repeatText}${productsText}: ${response.content.product_price_sum} kr${getDiscountsText(response.content.discounts)}. Saldo efter køb: ${response.content.new_user_balance} kr${getSharesText(response.content.shares)}`;
 }
 
 async function performQuickBuy(e) {
@@ -54,9 +89,28 @@ async function performQuickBuy(e) {
 
       outputMultiBuyPurchase(response.content);
     }
+
+    if (response.content.type === "Undo") {
+      const quickBuyErrorElement = document.getElementById("quickbuy-error");
+      console.assert(quickBuyErrorElement);
+      quickBuyErrorElement.innerText = "";
+
+      outputUndo(response.content);
+    }
+
+    if (response.content.type === "Repeat") {
+      const quickBuyErrorElement = document.getElementById("quickbuy-error");
+      console.assert(quickBuyErrorElement);
+      quickBuyErrorElement.innerText = "";
+
+      outputRepeat(response.content);
+    }
   }
   else {
+    // The field must be enabled before the error can select the offending part of it
+    enableQuickBuy();
     handleQuickBuyError(response.content);
+    return;
   }
 
   enableQuickBuy();
@@ -98,3 +152,28 @@ function getProductTooltip(product) {
   return "Aliasser:\n"
     + product.aliases.join("\n");
 }
+
+function outputMultiBuyPurchase(responseContent) {
+  const quickBuyOutputElement = document.getElementById("quickbuy-output");
+  console.assert(quickBuyOutputElement);
+
+  const productsText = responseContent.bought_products.map(p => `${p.amount} stk ${p.product_name} à ${p.unit_price} kr${getConsumerText(p)}`).join(", ");
+
+  quickBuyOutputElement.innerText += `${responseContent.username} har lige købt ${productsText} for tilsammen ${responseContent.product_price_sum} kr${getDiscountsText(responseContent.discounts)}${getSharesText(responseContent.shares)}\n`;
+}
+
+function outputRepeat(responseContent) {
+  const quickBuyOutputElement = document.getElementById("quickbuy-output");
+  console.assert(quickBuyOutputElement);
+
+  const productsText = responseContent.bought_products.map(p => `${p.amount} stk ${p.product_name} à ${p.unit_price} kr${getConsumerText(p)}`).join(", ");
+
+  quickBuyOutputElement.innerText += `${responseContent.username} har gentaget sit sidste køb og lige købt ${productsText} for tilsammen ${responseContent.product_price_sum} kr${getDiscountsText(responseContent.discounts)}. Saldo: ${responseContent.new_user_balance} kr${getSharesText(responseContent.shares)}\n`;
+}
+
+function outputUndo(responseContent) {
+  const quickBuyOutputElement = document.getElementById("quickbuy-output");
+  console.assert(quickBuyOutputElement);
+
+  quickBuyOutputElement.innerText += `${responseContent.username} har fortrudt sit sidste køb og fået ${responseContent.refunded_price_sum} kr tilbage. Saldo: ${responseContent.new_user_balance} kr\n`;
+}
@@ -1,5 +1,5 @@
-import { getActiveProducts, getUserInfo, postQuickBuy, isResponseOk, isResponseError } from "./api.js";
-import { populateTable, handleQuickBuyError } from "./product-table.js";
+import { getActiveProductCategories, getUserInfo, postQuickBuy, isResponseOk, isResponseError } from "./api.js";
+import { populateCategories, handleQuickBuyError, getConsumerText, getDiscountsText } from "./product-table.js";
 
 "use strict";
 
@@ -27,11 +27,11 @@ async function initializePage() {
 
     setUserBalance(userInfo.content.balance);
 
-    const activeProducts = await getActiveProducts();
+    const activeProductCategories = await getActiveProductCategories();
     // TODO: Error handling
-    const products = activeProducts.content.products;
-    window.products = products;
-    populateTable(products, (cell, product) => populateProductNameCell(cell, product, username));
+    const categories = activeProductCategories.content.categories;
+    window.products = categories.flatMap(c => c.products);
+    populateCategories(categories, (cell, product) => populateProductNameCell(cell, product, username));
   }
   catch (error) {
     console.error(error.message);
@@ -118,7 +118,7 @@ function outputMultiBuyPurchase(responseContent) {
   console.assert(quickBuyOutputElement);
 
   // TODO: Output "og" between the last elements
-  const productsText = boughtProducts.map(p => `${p.amount} stk ${window.products.find(f => f.id == p.product_id).name}`).join(", ");
+  const productsText = boughtProducts.map(p => `${p.amount} stk ${p.product_name}${getConsumerText(p)}`).join(", ");
 
-  quickBuyOutputElement.innerText += `${username} har lige købt ${productsText} for tilsammen ${productPriceSum} kr\n`;
+  quickBuyOutputElement.innerText += `${username} har lige købt ${productsText} for tilsammen ${productPriceSum} kr${getDiscountsText(responseContent.discounts)}\n`;
 }
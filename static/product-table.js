@@ -1,33 +1,89 @@
 "use strict";
 
-export function populateTable(products, productNamePopulator) {
-  const table1 = document.getElementById("products1").getElementsByTagName("tbody")[0];
-  const table2 = document.getElementById("products2").getElementsByTagName("tbody")[0];
-  for (const i in products) {
-    const row = createRow(products[i], productNamePopulator);
-    if (i % 2 === 0) {
-      table1.appendChild(row);
-    } else {
-      table2.appendChild(row);
-    }
+// Shown for the products that are not in any category
+const uncategorizedName = "Andet";
+
+export function populateCategories(categories, productNamePopulator) {
+  const productTables = document.getElementById("product-tables");
+  console.assert(productTables);
+
+  for (const category of categories) {
+    const section = document.createElement("section");
+    section.classList.add("product-category");
 
+    const heading = document.createElement("h3");
+    heading.innerText = category.name ?? uncategorizedName;
+    section.appendChild(heading);
+
+    section.appendChild(createTable(category.products, productNamePopulator));
+    productTables.appendChild(section);
   }
 }
 
+function createTable(products, productNamePopulator) {
+  const table = document.createElement("table");
+
+  const headerRow = document.createElement("tr");
+  for (const header of ["ID", "Produkt", "Pris"]) {
+    const headerCell = document.createElement("th");
+    headerCell.innerText = header;
+    headerRow.appendChild(headerCell);
+  }
+  table.createTHead().appendChild(headerRow);
+
+  const body = table.createTBody();
+  for (const product of products) {
+    body.appendChild(createRow(product, productNamePopulator));
+  }
+
+  return table;
+}
+
 function createRow(product, productNamePopulator) {
   const row = document.createElement("tr")
   const id = createTableCell(product.id);
   const name = createProductNameCell(product, productNamePopulator);
-  const price = createTableCell(`${product.price} kr`);
+  const price = createTableCell(product.sold_out ? "Udsolgt" : `${product.price} kr${getDiscountText(product)}${getQuotaText(product.quota)}`);
   row.appendChild(id);
   row.appendChild(name);
   row.appendChild(price);
   return row;
 }
 
+// Shows the normal price while a pricing rule such as a happy hour makes the product cheaper
+function getDiscountText(product) {
+  if (product.price === product.original_price) {
+    return "";
+  }
+
+  return ` (før ${product.original_price} kr)`;
+}
+
+const quotaPeriodTexts = {
+  "Day": "pr. dag",
+  "Event": "pr. arrangement",
+  "Ever": "i alt"
+};
+
+function getQuotaText(quota) {
+  if (quota == null) {
+    return "";
+  }
+
+  return ` (max ${quota.max_amount} ${quotaPeriodTexts[quota.period]})`;
+}
+
 function createProductNameCell(product, productNamePopulator) {
   const cell = document.createElement("td");
   productNamePopulator(cell, product);
+
+  if (product.age_restricted) {
+    const ageRestrictedElement = document.createElement("span");
+    ageRestrictedElement.classList.add("age-restricted");
+    ageRestrictedElement.innerText = " (18+)";
+    cell.appendChild(ageRestrictedElement);
+  }
+
   return cell;
 }
 
@@ -37,6 +93,31 @@ function createTableCell(text) {
   return cell;
 }
 
+// Describes who a bought product was for, when it was bought for someone else
+export function getConsumerText(product) {
+  return product.consumer == null ? "" : ` til ${product.consumer}`;
+}
+
+// Describes how a purchase split between several users was paid
+export function getSharesText(shares) {
+  if (shares.length <= 1) {
+    return "";
+  }
+
+  const sharesText = shares.map(s => `${s.username} betaler ${s.share} kr (saldo ${s.new_user_balance} kr)`).join(", ");
+  return `. Delt: ${sharesText}`;
+}
+
+// Describes the bundle discounts a purchase got
+export function getDiscountsText(discounts) {
+  if (discounts.length === 0) {
+    return "";
+  }
+
+  const discountsText = discounts.map(d => `${d.times} x ${d.name} -${d.discount} kr`).join(", ");
+  return `. Rabat: ${discountsText}`;
+}
+
 export function handleQuickBuyError(responseContent) {
   const quickBuyOutputElement = document.getElementById("quickbuy-output");
   console.assert(quickBuyOutputElement);
@@ -62,14 +143,17 @@ function handleQuickBuyParserError(responseContent) {
 
     case "Syntax":
       displayError("Syntax fejl");
+      markQuickBuySpan(responseContent.context.span);
       break;
 
     case "EmptyProduct":
       displayError("Tomt produkt navn");
+      markQuickBuySpan(responseContent.context.span);
       break;
 
     case "InvalidAmount":
       displayError("Ikke-positiv nummer af produkter angivet");
+      markQuickBuySpan(responseContent.context.span);
       break;
 
     default:
@@ -87,11 +171,17 @@ function handleQuickBuyExecutorError(responseContent) {
       break;
 
     case "InvalidUsername":
-      displayError(`Ukendt brugernavn: ${responseContent.context}`);
+      displayInvalidUsernameError(responseContent.context);
       break;
 
     case "InvalidProduct":
-      displayError(`Ukendt produkt: ${responseContent.context}`);
+      displayInvalidProductError(responseContent.context);
+      markQuickBuySpan(responseContent.context.span);
+      break;
+
+    case "AmbiguousProduct":
+      displayAmbiguousProductError(responseContent.context);
+      markQuickBuySpan(responseContent.context.span);
       break;
 
     case "InsufficientFunds":
@@ -102,12 +192,148 @@ function handleQuickBuyExecutorError(responseContent) {
       displayError("Overflow/underflow i stregcents");
       break;
 
+    case "AmountOverflow":
+      displayError("For mange stk af ét produkt");
+      break;
+
+    case "AgeRestricted":
+      displayError(`${responseContent.context.username} er ikke gammel nok til at købe ${responseContent.context.product_name}`);
+      markQuickBuySpan(responseContent.context.span);
+      break;
+
+    case "OutOfStock":
+      displayError(`Der er kun ${responseContent.context.available} stk ${responseContent.context.product_name} tilbage`);
+      markQuickBuySpan(responseContent.context.span);
+      break;
+
+    case "QuotaExceeded":
+      displayError(`${responseContent.context.username} kan kun få ${responseContent.context.remaining} stk ${responseContent.context.product_name} mere${getQuotaText(responseContent.context)}`);
+      markQuickBuySpan(responseContent.context.span);
+      break;
+
+    case "NoPurchaseToUndo":
+      displayError(`Intet nyligt køb at fortryde for ${responseContent.context}`);
+      break;
+
+    case "NoPurchaseToRepeat":
+      displayError(`Intet køb at gentage for ${responseContent.context}`);
+      break;
+
+    case "ProductUnavailable":
+      displayError(`${responseContent.context.product_name} kan ikke længere købes`);
+      break;
+
     default:
       displayError("Ukendt fejl. Se konsollen")
       break;
   }
 }
 
+function displayInvalidUsernameError(context) {
+  displayError(`Ukendt brugernavn: ${context.username}`);
+
+  if (context.suggestions.length === 0) {
+    return;
+  }
+
+  const quickBuyErrorElement = document.getElementById("quickbuy-error");
+  console.assert(quickBuyErrorElement);
+
+  quickBuyErrorElement.appendChild(document.createTextNode(". Mente du: "));
+  context.suggestions.forEach((suggestion, i) => {
+    if (i !== 0) {
+      quickBuyErrorElement.appendChild(document.createTextNode(", "));
+    }
+
+    const suggestionElement = document.createElement("a");
+    suggestionElement.href = "#";
+    suggestionElement.innerText = suggestion;
+    suggestionElement.addEventListener("click", e => {
+      e.preventDefault();
+      replaceQuickBuyUsername(suggestion);
+    });
+    quickBuyErrorElement.appendChild(suggestionElement);
+  });
+}
+
+function displayInvalidProductError(context) {
+  displayError(`Ukendt produkt: ${context.product_name}`);
+  appendProductSuggestions(context, context.suggestions);
+}
+
+function displayAmbiguousProductError(context) {
+  displayError(`Tvetydigt produkt: ${context.product_name}`);
+  appendProductSuggestions(context, context.candidates);
+}
+
+function appendProductSuggestions(context, suggestions) {
+  if (suggestions.length === 0) {
+    return;
+  }
+
+  const quickBuyErrorElement = document.getElementById("quickbuy-error");
+  console.assert(quickBuyErrorElement);
+
+  quickBuyErrorElement.appendChild(document.createTextNode(". Mente du: "));
+  suggestions.forEach((suggestion, i) => {
+    if (i !== 0) {
+      quickBuyErrorElement.appendChild(document.createTextNode(", "));
+    }
+
+    const suggestionElement = document.createElement("a");
+    suggestionElement.href = "#";
+    suggestionElement.innerText = suggestion.product_name;
+    suggestionElement.addEventListener("click", e => {
+      e.preventDefault();
+      replaceQuickBuyProduct(context.product_name, context.span, suggestion.quickbuy);
+    });
+    quickBuyErrorElement.appendChild(suggestionElement);
+  });
+}
+
+// Replaces the product name inside the span, keeping any count written around it
+function replaceQuickBuyProduct(productName, span, replacement) {
+  const quickBuyInput = document.getElementById("quickbuy-field");
+  if (quickBuyInput == null) {
+    return;
+  }
+
+  const characters = Array.from(quickBuyInput.value);
+  const before = characters.slice(0, span.char_start).join("");
+  const product = characters.slice(span.char_start, span.char_end).join("");
+  const after = characters.slice(span.char_end).join("");
+
+  quickBuyInput.value = before + product.replace(productName, replacement) + after;
+  quickBuyInput.focus();
+}
+
+function replaceQuickBuyUsername(replacement) {
+  const quickBuyInput = document.getElementById("quickbuy-field");
+  if (quickBuyInput == null) {
+    return;
+  }
+
+  const [, ...products] = quickBuyInput.value.split(" ");
+  quickBuyInput.value = [replacement, ...products].join(" ");
+  quickBuyInput.focus();
+}
+
+// Selects the offending part of the quickbuy so it stands out in the field
+function markQuickBuySpan(span) {
+  const quickBuyInput = document.getElementById("quickbuy-field");
+  if (quickBuyInput == null) {
+    return;
+  }
+
+  // The span counts characters, the input counts UTF-16 code units
+  const characters = Array.from(quickBuyInput.value);
+  const start = characters.slice(0, span.char_start).join("").length;
+  const end = characters.slice(0, span.char_end).join("").length;
+
+  quickBuyInput.focus();
+  quickBuyInput.setSelectionRange(start, end);
+}
+
 function displayError(text) {
   const quickBuyErrorElement = document.getElementById("quickbuy-error");
   console.assert(quickBuyErrorElement);